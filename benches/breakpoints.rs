@@ -0,0 +1,89 @@
+// Copyright 2020 Solana Maintainers <maintainers@solana.com>
+//
+// Licensed under the Apache License, Version 2.0 <http://www.apache.org/licenses/LICENSE-2.0> or
+// the MIT license <http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+#![feature(test)]
+
+extern crate solana_rbpf;
+extern crate test;
+
+use solana_rbpf::BreakpointTable;
+use test::Bencher;
+
+/// Every instruction index of the program is looked up, as the interpreter does
+const PROGRAM_LEN: u64 = 1024;
+
+/// Spreads `count` breakpoints over the program
+fn breakpoint_addrs(count: u64) -> Vec<u64> {
+    (0..count).map(|i| i * PROGRAM_LEN / count + 3).collect()
+}
+
+fn new_table(count: u64, thresh: usize) -> BreakpointTable {
+    let mut table = BreakpointTable::with_threshold(thresh);
+    for addr in breakpoint_addrs(count) {
+        table.set_breakpoint(addr);
+    }
+    table
+}
+
+fn bench_lookups(bencher: &mut Bencher, table: &BreakpointTable) {
+    bencher.iter(|| {
+        (0..PROGRAM_LEN)
+            .filter(|pc| table.check_breakpoint(test::black_box(*pc)))
+            .count()
+    });
+}
+
+#[bench]
+fn bench_few_linear(bencher: &mut Bencher) {
+    let addrs = breakpoint_addrs(5);
+    bencher.iter(|| {
+        (0..PROGRAM_LEN)
+            .filter(|pc| addrs.contains(&test::black_box(*pc)))
+            .count()
+    });
+}
+
+#[bench]
+fn bench_few_sorted(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(5, usize::MAX));
+}
+
+#[bench]
+fn bench_few_hash_map(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(5, 0));
+}
+
+// The sorted table against the hash map it switches to around the default threshold of 30
+
+#[bench]
+fn bench_sorted_10(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(10, usize::MAX));
+}
+
+#[bench]
+fn bench_hash_map_10(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(10, 0));
+}
+
+#[bench]
+fn bench_sorted_30(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(30, usize::MAX));
+}
+
+#[bench]
+fn bench_hash_map_30(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(30, 0));
+}
+
+#[bench]
+fn bench_sorted_100(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(100, usize::MAX));
+}
+
+#[bench]
+fn bench_hash_map_100(bencher: &mut Bencher) {
+    bench_lookups(bencher, &new_table(100, 0));
+}
@@ -10,6 +10,7 @@ extern crate solana_rbpf;
 extern crate test;
 
 use solana_rbpf::{
+    assembler::assemble,
     user_error::UserError,
     vm::{Config, DefaultInstructionMeter, EbpfVm, Executable},
 };
@@ -33,6 +34,62 @@ fn bench_init_interpreter_execution(bencher: &mut Bencher) {
     });
 }
 
+/// Long enough for the per instruction checks to outweigh starting the debugger
+const LOOP_ASM: &str = "
+    mov r1, 100000
+    sub r1, 1
+    jne r1, 0, -2
+    exit";
+
+#[bench]
+fn bench_interpreter_loop(bencher: &mut Bencher) {
+    let program = assemble(LOOP_ASM).unwrap();
+    let executable = Executable::<UserError, DefaultInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm =
+        EbpfVm::<UserError, DefaultInstructionMeter>::new(executable.as_ref(), &mut [], &[])
+            .unwrap();
+    bencher.iter(|| {
+        vm.execute_program_interpreted(&mut DefaultInstructionMeter {})
+            .unwrap()
+    });
+}
+
+/// Same as bench_interpreter_loop with GDB attached but no breakpoints set
+#[cfg(feature = "debug")]
+#[bench]
+fn bench_interpreter_loop_with_debugger(bencher: &mut Bencher) {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport, PipeConnection};
+
+    let program = assemble(LOOP_ASM).unwrap();
+    let executable = Executable::<UserError, DefaultInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm =
+        EbpfVm::<UserError, DefaultInstructionMeter>::new(executable.as_ref(), &mut [], &[])
+            .unwrap();
+    bencher.iter(|| {
+        let (server, mut gdb) = PipeConnection::pair();
+        vm.set_debugger(DebugServerBuilder::new(DebugTransport::Connection(
+            Box::new(server),
+        )));
+        let gdb = std::thread::spawn(move || {
+            gdb.send_packet(b"c").unwrap();
+            gdb.recv_packet().unwrap()
+        });
+        vm.execute_program_interpreted(&mut DefaultInstructionMeter {})
+            .unwrap();
+        gdb.join().unwrap()
+    });
+}
+
 #[cfg(not(windows))]
 #[bench]
 fn bench_init_jit_execution(bencher: &mut Bencher) {
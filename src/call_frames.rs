@@ -14,6 +14,15 @@ struct CallFrame {
     return_ptr: usize,
 }
 
+/// The frame bookkeeping of `CallFrames` without the stack contents,
+/// as taken by `CallFrames::save`
+#[derive(Clone, Debug)]
+pub struct CallFramesState {
+    frame_index: usize,
+    frame_index_max: usize,
+    frames: Vec<CallFrame>,
+}
+
 /// When BPF calls a function other then a `syscall` it expect the new
 /// function to be called in its own frame.  CallFrames manages
 /// call frames
@@ -77,6 +86,36 @@ impl CallFrames {
         self.frame_index_max
     }
 
+    /// Get the return pointer saved by each active caller frame, root frame first
+    pub fn get_return_ptrs(&self) -> Vec<usize> {
+        self.frames[..self.frame_index]
+            .iter()
+            .map(|frame| frame.return_ptr)
+            .collect()
+    }
+
+    /// Drop every frame but the root one
+    pub fn reset(&mut self) {
+        self.frame_index = 0;
+        self.frame_index_max = 0;
+    }
+
+    /// Save the active frames, their saved registers and return pointers
+    pub fn save(&self) -> CallFramesState {
+        CallFramesState {
+            frame_index: self.frame_index,
+            frame_index_max: self.frame_index_max,
+            frames: self.frames.clone(),
+        }
+    }
+
+    /// Go back to the frames taken by `save`
+    pub fn restore(&mut self, state: &CallFramesState) {
+        self.frame_index = state.frame_index;
+        self.frame_index_max = state.frame_index_max;
+        self.frames.clone_from(&state.frames);
+    }
+
     /// Push a frame
     pub fn push<E: UserDefinedError>(
         &mut self,
@@ -140,6 +179,7 @@ mod tests {
         ptrs.push(frames.get_frame_pointers()[i]);
 
         assert!(frames.push::<UserError>(&registers, DEPTH - 1).is_err());
+        assert_eq!(frames.get_return_ptrs(), (0..DEPTH - 1).collect::<Vec<_>>());
 
         for i in (0..DEPTH - 1).rev() {
             let (saved_reg, stack_ptr, return_ptr) = frames.pop::<UserError>().unwrap();
@@ -150,4 +190,20 @@ mod tests {
 
         assert!(frames.pop::<UserError>().is_err());
     }
+
+    #[test]
+    fn test_save_restore() {
+        let mut frames = CallFrames::new(4, 8);
+        frames.push::<UserError>(&[1, 2, 3, 4], 5).unwrap();
+        let state = frames.save();
+        frames.push::<UserError>(&[6, 7, 8, 9], 10).unwrap();
+        assert_eq!(frames.get_frame_index(), 2);
+
+        frames.restore(&state);
+        assert_eq!(frames.get_frame_index(), 1);
+        assert_eq!(frames.get_return_ptrs(), vec![5]);
+        let (saved_reg, _, return_ptr) = frames.pop::<UserError>().unwrap();
+        assert_eq!(saved_reg, [1, 2, 3, 4]);
+        assert_eq!(return_ptr, 5);
+    }
 }
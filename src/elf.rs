@@ -165,6 +165,7 @@ impl BPFRelocationType {
 #[derive(Debug, PartialEq)]
 struct SectionInfo {
     vaddr: u64,
+    elf_vaddr: u64,
     offset_range: Range<usize>,
 }
 
@@ -221,6 +222,15 @@ impl<E: UserDefinedError, I: InstructionMeter> Executable<E, I> for EBpfElf<E, I
             .collect::<Result<Vec<_>, EbpfError<E>>>()
     }
 
+    /// Get how far the .text section and the read-only sections were moved from their ELF addresses
+    fn get_section_offsets(&self) -> (u64, Option<u64>) {
+        let offset = |info: &SectionInfo| info.vaddr - info.elf_vaddr;
+        (
+            offset(&self.text_section_info),
+            self.ro_section_infos.first().map(offset),
+        )
+    }
+
     /// Get the entry point offset into the text section
     fn get_entrypoint_instruction_offset(&self) -> Result<usize, EbpfError<E>> {
         Ok(self.entrypoint)
@@ -328,6 +338,7 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EBpfElf<E, I> {
             entrypoint: 0,
             text_section_info: SectionInfo {
                 vaddr: ebpf::MM_PROGRAM_START,
+                elf_vaddr: 0,
                 offset_range: Range {
                     start: 0,
                     end: text_bytes.len(),
@@ -361,6 +372,7 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EBpfElf<E, I> {
         // calculate the text section info
         let text_section_info = SectionInfo {
             vaddr: text_section.sh_addr.saturating_add(ebpf::MM_PROGRAM_START),
+            elf_vaddr: text_section.sh_addr,
             offset_range: text_section.file_range(),
         };
 
@@ -378,6 +390,7 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EBpfElf<E, I> {
                             vaddr: section_header
                                 .sh_addr
                                 .saturating_add(ebpf::MM_PROGRAM_START),
+                            elf_vaddr: section_header.sh_addr,
                             offset_range: section_header.file_range(),
                         });
                     }
@@ -640,6 +653,21 @@ mod test {
     use std::{collections::HashMap, fs::File, io::Read};
     type ElfExecutable = EBpfElf<UserError, DefaultInstructionMeter>;
 
+    #[test]
+    fn test_section_offsets() {
+        let mut file = File::open("tests/elfs/noop.so").expect("file open failed");
+        let mut elf_bytes = Vec::new();
+        file.read_to_end(&mut elf_bytes)
+            .expect("failed to read elf file");
+        let elf = ElfExecutable::load(Config::default(), &elf_bytes).expect("validation failed");
+        assert_eq!(
+            elf.get_section_offsets(),
+            (ebpf::MM_PROGRAM_START, Some(ebpf::MM_PROGRAM_START))
+        );
+        let elf = ElfExecutable::new_from_text_bytes(Config::default(), &[0; ebpf::INSN_SIZE]);
+        assert_eq!(elf.get_section_offsets(), (ebpf::MM_PROGRAM_START, None));
+    }
+
     #[test]
     fn test_validate() {
         let mut file = File::open("tests/elfs/noop.so").expect("file open failed");
@@ -80,4 +80,12 @@ pub enum EbpfError<E: UserDefinedError> {
     /// Unsupported instruction
     #[error("unsupported instruction at instruction {0}")]
     UnsupportedInstruction(usize),
+    /// Execution was killed by an attached debugger
+    #[cfg(feature = "debug")]
+    #[error("execution killed by the debugger at instruction #{0}")]
+    KilledByDebugger(usize),
+    /// The debugger the run was configured with could not be started
+    #[cfg(feature = "debug")]
+    #[error("could not start the debugger: {0}")]
+    DebuggerFailed(String),
 }
@@ -1,440 +1,4141 @@
-use byteorder::{LittleEndian, ReadBytesExt};
+use crate::{
+    call_frames::CallFramesState,
+    disassembler, ebpf,
+    error::{EbpfError, UserDefinedError},
+};
 use gdbstub::{
     arch::{Arch, RegId, Registers},
+    common::Pid,
+    outputln,
     target::{
         ext::{
             base::{
                 singlethread::{ResumeAction, SingleThreadOps, StopReason},
                 BaseOps,
             },
-            breakpoints::{SwBreakpoint, SwBreakpointOps},
+            breakpoints::{
+                HwWatchpoint, HwWatchpointOps, SwBreakpoint, SwBreakpointOps, WatchKind,
+            },
+            extended_mode::{Args, ExtendedMode, ExtendedModeOps, ShouldTerminate},
+            monitor_cmd::{ConsoleOutput, MonitorCmd, MonitorCmdOps},
             section_offsets::{Offsets, SectionOffsets, SectionOffsetsOps},
         },
         Target, TargetError, TargetResult,
     },
-    DisconnectReason, GdbStub, GdbStubError,
+    Connection, DisconnectReason, GdbStubBuilder, GdbStubError,
 };
-use std::collections::HashSet;
-use std::debug_assert;
-use std::io::Cursor;
+use log::{debug, info, log_enabled, trace, warn, Level};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::mpsc;
+#[cfg(unix)]
+use std::os::unix::{
+    fs::OpenOptionsExt,
+    io::AsRawFd,
+    net::{UnixListener, UnixStream},
+};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc, Arc,
+};
+use std::thread;
+use std::time::Duration;
 
 const BRPKT_MAP_THRESH: usize = 30;
 
+// the VM is the only process the debugger can see
+const VM_PID: Pid = Pid::new(1).unwrap();
+
+// how often a Continue checks GDB for an interrupt
+const RESUME_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+// how long the server waits for the VM to answer a request
+const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// the oldest checkpoints are dropped once their memory copies exceed this
+const MAX_SNAPSHOT_BYTES: usize = 64 * 1024 * 1024;
+
+// instructions shown by `monitor disas` without a count
+const DISAS_DEFAULT_COUNT: usize = 10;
+
+// granularity of the server's memory read cache
+const CACHE_PAGE_SIZE: u64 = 1024;
+
+// r0..r10, the pc is appended as an extra register after them
 const NUM_REGS: usize = 11;
-const NUM_REGS_WITH_PC: usize = 12;
+const NUM_REGS_WITH_PC: usize = NUM_REGS + 1;
 const REG_SIZE: usize = 8;
 const REG_NUM_BYTES: usize = NUM_REGS * REG_SIZE;
-const REG_WITH_PC_NUM_BYTES: usize = NUM_REGS * REG_SIZE;
-
-// TODO make this not use unwrap
-// TODO add support for Unix Domain Sockets
-pub fn start_debug_server(
-    port: u16,
-    init_regs: &[u64; 11],
-    init_pc: u64,
-) -> (mpsc::SyncSender<VmReply>, mpsc::Receiver<VmRequest>) {
-    let conn = wait_for_gdb_connection(port).unwrap();
-    let (mut target, tx, rx) = DebugServer::new(init_regs, init_pc);
-
-    std::thread::spawn(move || {
-        let mut debugger = GdbStub::new(conn);
-
-        match debugger.run(&mut target) {
-            Ok(disconnect_reason) => match disconnect_reason {
-                DisconnectReason::Disconnect => println!("GDB client disconnected."),
-                DisconnectReason::TargetHalted => println!("Target halted!"),
-                DisconnectReason::Kill => println!("GDB client sent a kill command!"),
-            },
-            // Handle any target-specific errors
-            Err(GdbStubError::TargetError(e)) => {
-                println!("Target raised a fatal error: {:?}", e);
-                // e.g: re-enter the debugging session after "freezing" a system to
-                // conduct some post-mortem debugging
-                debugger.run(&mut target).unwrap();
-            }
-            Err(e) => {
-                eprint!("Could not run Target {:?}\n", e);
-            }
-        }
-    });
+const REG_WITH_PC_NUM_BYTES: usize = NUM_REGS_WITH_PC * REG_SIZE;
 
-    (tx, rx)
+/// Errors of the debug server and of its link to the VM
+#[derive(Debug, thiserror::Error)]
+pub enum DebugError {
+    /// The transport could not be set up or GDB did not connect
+    #[error("could not open the debug connection: {0}")]
+    Connection(#[from] std::io::Error),
+    /// The VM did not answer a request in time
+    #[error("the VM did not reply within {0:?}")]
+    Timeout(Duration),
+    /// The VM or the server thread has gone away
+    #[error("the other end of the debug session has gone away")]
+    ChannelClosed,
 }
 
-fn wait_for_gdb_connection(port: u16) -> std::io::Result<TcpStream> {
-    let sockaddr = format!("localhost:{}", port);
-    eprintln!("Waiting for a GDB connection on {:?}...", sockaddr);
-    let sock = TcpListener::bind(sockaddr)?;
-    let (stream, addr) = sock.accept()?;
+impl<T> From<mpsc::SendError<T>> for DebugError {
+    fn from(_: mpsc::SendError<T>) -> Self {
+        DebugError::ChannelClosed
+    }
+}
 
-    // Blocks until a GDB client connects via TCP.
-    // i.e: Running `target remote localhost:<port>` from the GDB prompt.
+impl DebugError {
+    /// gdbstub only takes static strings as target errors
+    fn as_str(&self) -> &'static str {
+        match self {
+            DebugError::Connection(_) => "could not open the debug connection",
+            DebugError::Timeout(_) => "the VM did not reply in time",
+            DebugError::ChannelClosed => "the VM is no longer running",
+        }
+    }
+}
 
-    eprintln!("Debugger connected from {}", addr);
-    Ok(stream)
+impl From<DebugError> for TargetError<&'static str> {
+    fn from(e: DebugError) -> Self {
+        match e {
+            // GDB gets an error reply and the session stays up
+            DebugError::Timeout(_) => TargetError::NonFatal,
+            e => TargetError::Fatal(e.as_str()),
+        }
+    }
 }
 
-pub enum BreakpointTable {
-    Few(Vec<u64>),
-    Many(HashSet<u64>),
+/// Where the debug server waits for GDB
+pub enum DebugTransport {
+    /// TCP port on localhost, `target remote :<port>`
+    Tcp(u16),
+    /// Unix domain socket path, `target remote <path>`
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// Serial device path and baud rate
+    #[cfg(unix)]
+    Serial(PathBuf, u32),
+    /// A connection the embedder has already set up, e.g. an in-process pipe or a
+    /// transport gdbstub doesn't know about. Served for a single session
+    Connection(Box<dyn Connection<Error = std::io::Error> + Send>),
 }
 
-impl BreakpointTable {
-    pub fn new() -> Self {
-        BreakpointTable::Few(Vec::new())
+pub type DebugChannels = (mpsc::SyncSender<VmReply>, mpsc::Receiver<VmRequest>);
+
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+const SIGXCPU: u8 = 24;
+
+/// The POSIX signal GDB is shown for a fault raised by the VM
+pub fn fault_signal<E: UserDefinedError>(err: &EbpfError<E>) -> u8 {
+    match err {
+        EbpfError::AccessViolation(..)
+        | EbpfError::StackAccessViolation(..)
+        | EbpfError::InvalidVirtualAddress(_)
+        | EbpfError::CallDepthExceeded(..) => SIGSEGV,
+        EbpfError::DivideByZero(_) => SIGFPE,
+        EbpfError::InvalidInstruction(_)
+        | EbpfError::UnsupportedInstruction(_)
+        | EbpfError::ExecutionOverrun(_)
+        | EbpfError::CallOutsideTextSegment(..)
+        | EbpfError::ExitRootCallFrame => SIGILL,
+        EbpfError::ExceededMaxInstructions(..) => SIGXCPU,
+        _ => SIGABRT,
     }
+}
 
-    pub fn check_breakpoint(&self, addr: u64) -> bool {
-        match &*self {
-            BreakpointTable::Few(addrs) => {
-                for brkpt_addr in addrs.iter() {
-                    if *brkpt_addr == addr {
-                        return true;
-                    }
-                }
-                return false;
-            }
-            BreakpointTable::Many(addrs) => addrs.contains(&addr),
+/// Configures the debug server, hand it to `EbpfVm::set_debugger` to debug the next
+/// interpreted run
+pub struct DebugServerBuilder {
+    transport: DebugTransport,
+    brkpt_threshold: usize,
+    max_packet_size: Option<usize>,
+    reply_timeout: Duration,
+    short_read_policy: ShortReadPolicy,
+    max_sessions: Option<usize>,
+    disconnect_policy: DisconnectPolicy,
+    mem_cache_pages: Option<usize>,
+    max_write_size: Option<usize>,
+    on_disconnect: Option<DisconnectHook>,
+}
+
+type DisconnectHook = Box<dyn Fn(DisconnectReason) + Send>;
+
+/// What happens to the VM when the connection to GDB drops, a GDB that detaches
+/// always leaves the VM running on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectPolicy {
+    /// the VM stays stopped until the next GDB connects
+    Stop,
+    /// the VM runs on without a debugger and no further GDB is accepted
+    Detach,
+}
+
+/// What the server answers when the VM can only read part of the memory GDB asked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShortReadPolicy {
+    /// an error reply, GDB reports the memory as inaccessible
+    Error,
+    /// the bytes that could be read, padded with zeros
+    ZeroFill,
+}
+
+impl DebugServerBuilder {
+    /// A server waiting for GDB on `transport` with the default settings
+    pub fn new(transport: DebugTransport) -> Self {
+        DebugServerBuilder {
+            transport,
+            brkpt_threshold: BRPKT_MAP_THRESH,
+            max_packet_size: None,
+            reply_timeout: DEFAULT_REPLY_TIMEOUT,
+            short_read_policy: ShortReadPolicy::Error,
+            max_sessions: None,
+            disconnect_policy: DisconnectPolicy::Stop,
+            mem_cache_pages: None,
+            max_write_size: None,
+            on_disconnect: None,
         }
     }
 
-    pub fn set_breakpoint(&mut self, addr: u64) {
-        match *self {
-            BreakpointTable::Few(ref mut addrs) => {
-                if addrs.len() > BRPKT_MAP_THRESH {
-                    let mut set = HashSet::<u64>::with_capacity(addrs.len() + 1);
-                    set.insert(addr);
-                    for addr in addrs.iter() {
-                        set.insert(*addr);
-                    }
-                    *self = BreakpointTable::Many(set);
-                } else {
-                    addrs.push(addr);
-                }
-            }
-            BreakpointTable::Many(ref mut addrs) => {
-                addrs.insert(addr);
-            }
-        }
+    /// Number of breakpoints above which the VM switches to a hash map for lookups
+    pub fn brkpt_threshold(mut self, brkpt_threshold: usize) -> Self {
+        self.brkpt_threshold = brkpt_threshold;
+        self
     }
 
-    pub fn remove_breakpoint(&mut self, addr: u64) {
-        match *self {
-            BreakpointTable::Few(ref mut addrs) => {
-                if let Some(i) =
-                    addrs
-                        .iter()
-                        .enumerate()
-                        .find_map(|(i, address)| if *address == addr { Some(i) } else { None })
-                {
-                    addrs.remove(i);
-                }
+    /// Size of the packet buffer advertised to GDB, gdbstub picks one if unset
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    /// How long the server waits for the VM to answer before failing the GDB request
+    pub fn reply_timeout(mut self, reply_timeout: Duration) -> Self {
+        self.reply_timeout = reply_timeout;
+        self
+    }
+
+    /// How to answer memory reads that run past the readable memory, errors by default
+    pub fn short_read_policy(mut self, short_read_policy: ShortReadPolicy) -> Self {
+        self.short_read_policy = short_read_policy;
+        self
+    }
+
+    /// Number of GDB sessions to serve, unlimited if unset. When the connection drops and
+    /// sessions remain the VM stays stopped, with its breakpoints, until GDB connects
+    /// again; after the last one it runs on without a debugger
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// What to do with the VM once the connection to GDB drops, it waits for the next
+    /// GDB by default
+    pub fn disconnect_policy(mut self, disconnect_policy: DisconnectPolicy) -> Self {
+        self.disconnect_policy = disconnect_policy;
+        self
+    }
+
+    /// Caches up to this many pages of VM memory while the VM is stopped, so GDB's bursts
+    /// of small reads don't each go to the VM. Off by default
+    pub fn mem_cache_pages(mut self, mem_cache_pages: usize) -> Self {
+        self.mem_cache_pages = Some(mem_cache_pages);
+        self
+    }
+
+    /// Largest memory write accepted from GDB, larger ones get an E02 reply without
+    /// reaching the VM. Defaults to the packet size if one is set, otherwise unlimited
+    pub fn max_write_size(mut self, max_write_size: usize) -> Self {
+        self.max_write_size = Some(max_write_size);
+        self
+    }
+
+    /// Called on the server thread whenever a GDB session ends, a dropped connection is
+    /// reported as `Disconnect`. By default the reason is printed
+    pub fn on_disconnect<F>(mut self, on_disconnect: F) -> Self
+    where
+        F: Fn(DisconnectReason) + Send + 'static,
+    {
+        self.on_disconnect = Some(Box::new(on_disconnect));
+        self
+    }
+
+    /// Waits for GDB to connect and returns the VM's end of the session
+    pub fn start(
+        self,
+        init_regs: &[u64; NUM_REGS],
+        init_pc: u64,
+    ) -> Result<DebugInterface, DebugError> {
+        let brkpt_threshold = self.brkpt_threshold;
+        let channels = self.spawn(init_regs, init_pc)?;
+        Ok(DebugInterface::with_brkpt_threshold(
+            channels,
+            brkpt_threshold,
+        ))
+    }
+
+    fn build_server(
+        &mut self,
+        init_regs: &[u64; NUM_REGS],
+        init_pc: u64,
+    ) -> (DebugServer, DebugChannels, SessionConfig) {
+        let (mut target, tx, rx) = DebugServer::new(init_regs, init_pc, self.reply_timeout);
+        target.short_read_policy = self.short_read_policy;
+        target.mem_cache = self.mem_cache_pages.map(MemCache::new);
+        target.max_write_size = self.max_write_size.or(self.max_packet_size);
+        let sessions = SessionConfig {
+            max_packet_size: self.max_packet_size,
+            max_sessions: self.max_sessions,
+            disconnect_policy: self.disconnect_policy,
+            on_disconnect: self
+                .on_disconnect
+                .take()
+                .unwrap_or_else(|| Box::new(report_disconnect)),
+        };
+        (target, (tx, rx), sessions)
+    }
+
+    fn spawn(
+        mut self,
+        init_regs: &[u64; NUM_REGS],
+        init_pc: u64,
+    ) -> Result<DebugChannels, DebugError> {
+        let (target, channels, mut sessions) = self.build_server(init_regs, init_pc);
+        match self.transport {
+            DebugTransport::Tcp(port) => {
+                let sock = listen_for_gdb(port)?;
+                let conn = accept_gdb_connection(&sock)?;
+                spawn_debug_server(conn, move || accept_gdb_connection(&sock), sessions, target)
+            }
+            #[cfg(unix)]
+            DebugTransport::Unix(path) => {
+                let sock = listen_for_gdb_unix(&path)?;
+                let conn = accept_gdb_unix_connection(&sock, &path)?;
+                spawn_debug_server(
+                    conn,
+                    move || accept_gdb_unix_connection(&sock, &path),
+                    sessions,
+                    target,
+                )
+            }
+            #[cfg(unix)]
+            DebugTransport::Serial(path, baud) => {
+                let conn = SerialConnection::open(&path, baud)?;
+                spawn_debug_server(
+                    conn,
+                    move || SerialConnection::open(&path, baud),
+                    sessions,
+                    target,
+                )
             }
-            BreakpointTable::Many(ref mut addrs) => {
-                addrs.remove(&addr);
+            DebugTransport::Connection(conn) => {
+                sessions.max_sessions = Some(1);
+                spawn_debug_server(
+                    BoxedConnection(conn),
+                    || Err(std::io::ErrorKind::NotConnected.into()),
+                    sessions,
+                    target,
+                )
             }
         }
+        Ok(channels)
     }
 }
 
-pub struct DebugServer {
-    req: mpsc::SyncSender<VmRequest>,
-    reply: mpsc::Receiver<VmReply>,
-    regs: BPFRegs,
+struct SessionConfig {
+    max_packet_size: Option<usize>,
+    max_sessions: Option<usize>,
+    disconnect_policy: DisconnectPolicy,
+    on_disconnect: DisconnectHook,
 }
 
-impl DebugServer {
-    fn new(
-        regs: &[u64; 11],
-        pc: u64,
-    ) -> (Self, mpsc::SyncSender<VmReply>, mpsc::Receiver<VmRequest>) {
-        let (req_tx, req_rx) = mpsc::sync_channel::<VmRequest>(0);
-        let (reply_tx, reply_rx) = mpsc::sync_channel::<VmReply>(0);
-        (
-            DebugServer {
-                req: req_tx,
-                reply: reply_rx,
-                regs: BPFRegs {
-                    regs: *regs,
-                    pc: pc,
-                },
-            },
-            reply_tx,
-            req_rx,
-        )
+fn report_disconnect(reason: DisconnectReason) {
+    match reason {
+        DisconnectReason::Disconnect => println!("GDB client disconnected."),
+        DisconnectReason::TargetHalted => println!("Target halted!"),
+        DisconnectReason::Kill => println!("GDB client sent a kill command!"),
     }
 }
 
-#[derive(Debug, Clone, Default, PartialEq)]
-#[repr(C)]
-pub struct BPFRegs {
-    regs: [u64; 11],
-    pc: u64,
+/// Serves GDB sessions one after another on the server thread, `reconnect` waits for
+/// the next GDB once the current one has disconnected
+fn spawn_debug_server<C, F>(
+    conn: C,
+    mut reconnect: F,
+    config: SessionConfig,
+    mut target: DebugServer,
+) where
+    C: 'static + Connection + Send,
+    C::Error: std::fmt::Debug,
+    F: 'static + FnMut() -> std::io::Result<C> + Send,
+{
+    thread::spawn(move || {
+        let mut conn = conn;
+        let mut sessions = 1;
+        while run_debug_session(conn, &config, &mut target) {
+            if config.disconnect_policy == DisconnectPolicy::Detach
+                || config.max_sessions.is_some_and(|max| sessions >= max)
+            {
+                target.detach();
+                return;
+            }
+            conn = match reconnect() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Could not wait for another GDB connection {:?}", e);
+                    target.detach();
+                    return;
+                }
+            };
+            sessions += 1;
+        }
+    });
 }
 
-// TODO use something safer than transmute_copy
-impl Registers for BPFRegs {
-    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
-        let bytes: [u8; REG_WITH_PC_NUM_BYTES] = unsafe { std::mem::transmute_copy(self) };
-        bytes.iter().for_each(|b| write_byte(Some(*b)));
+/// Returns true if the connection to GDB dropped and left the VM to a later session
+fn run_debug_session<C>(conn: C, config: &SessionConfig, target: &mut DebugServer) -> bool
+where
+    C: Connection,
+    C::Error: std::fmt::Debug,
+{
+    target.conn_lost.store(false, Ordering::Relaxed);
+    let conn = WatchedConnection {
+        inner: conn,
+        lost: target.conn_lost.clone(),
+        watch_len: target.watch_len.clone(),
+        packet: None,
+    };
+    let mut builder = GdbStubBuilder::new(conn);
+    if let Some(size) = config.max_packet_size {
+        builder = builder.packet_buffer_size(size);
     }
+    let mut debugger = match builder.build() {
+        Ok(debugger) => debugger,
+        Err(e) => {
+            warn!("Could not configure the GDB stub {:?}", e);
+            return false;
+        }
+    };
 
-    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
-        let mut rdr = Cursor::new(bytes);
-        let mut acc = BPFRegs::default();
-        for i in 0..NUM_REGS {
-            if let Ok(u) = rdr.read_u64::<LittleEndian>() {
-                acc.regs[i] = u;
-            } else {
-                return Err(());
+    match debugger.run(target) {
+        Ok(disconnect_reason) => {
+            (config.on_disconnect)(disconnect_reason);
+            match disconnect_reason {
+                // GDB detached on purpose, the VM runs on whatever the policy
+                DisconnectReason::Disconnect => target.detach(),
+                DisconnectReason::TargetHalted => {}
+                DisconnectReason::Kill => target.kill(),
             }
         }
-        if let Ok(u) = rdr.read_u64::<LittleEndian>() {
-            acc.pc = u;
-            *self = acc;
-            Ok(())
-        } else {
-            Err(())
+        // Handle any target-specific errors
+        Err(GdbStubError::TargetError(e)) => {
+            println!("Target raised a fatal error: {:?}", e);
+            // e.g: re-enter the debugging session after "freezing" a system to
+            // conduct some post-mortem debugging
+            if let Err(e) = debugger.run(target) {
+                warn!("Could not resume debugging session {:?}", e);
+            }
+        }
+        Err(GdbStubError::ConnectionRead(e)) => {
+            warn!("Lost the connection to GDB {:?}", e);
+            (config.on_disconnect)(DisconnectReason::Disconnect);
+            return true;
+        }
+        Err(GdbStubError::ConnectionWrite(e)) => {
+            warn!("Lost the connection to GDB {:?}", e);
+            (config.on_disconnect)(DisconnectReason::Disconnect);
+            return true;
+        }
+        Err(e) => {
+            eprintln!("Could not run Target {:?}", e);
         }
     }
+    false
 }
 
-#[derive(Debug)]
-pub struct BPFRegId(u8);
-impl RegId for BPFRegId {
-    fn from_raw_id(id: usize) -> Option<(Self, usize)> {
-        if id < 13 {
-            Some((BPFRegId(id as u8), 64))
-        } else {
-            None
+/// Passes everything through to `inner` and flags the first error, so a running VM can
+/// be stopped once GDB is gone instead of waiting on a connection nobody reads.
+/// It also picks the length out of watchpoint packets, which gdbstub 0.4 parses but
+/// doesn't hand to the target.
+struct WatchedConnection<C> {
+    inner: C,
+    lost: Arc<AtomicBool>,
+    watch_len: Arc<AtomicU64>,
+    /// the packet read so far, only kept while it can still be a Z or z packet
+    packet: Option<Vec<u8>>,
+}
+
+impl<C: Connection> WatchedConnection<C> {
+    fn watch<T>(&self, result: Result<T, C::Error>) -> Result<T, C::Error> {
+        if result.is_err() {
+            self.lost.store(true, Ordering::Relaxed);
         }
+        result
     }
-}
 
-impl From<u8> for BPFRegId {
-    fn from(val: u8) -> BPFRegId {
-        BPFRegId(val)
+    fn scan(&mut self, byte: u8) {
+        match byte {
+            b'$' => self.packet = Some(Vec::new()),
+            b'#' => {
+                if let Some(len) = self.packet.take().and_then(|p| watch_packet_len(&p)) {
+                    self.watch_len.store(len, Ordering::Relaxed);
+                }
+            }
+            _ => {
+                if let Some(packet) = self.packet.as_mut() {
+                    if (packet.is_empty() && byte != b'Z' && byte != b'z') || packet.len() > 40 {
+                        self.packet = None;
+                    } else {
+                        packet.push(byte);
+                    }
+                }
+            }
+        }
     }
 }
 
-impl From<BPFRegId> for u8 {
-    fn from(val: BPFRegId) -> u8 {
-        val.0
+/// Returns the length of a `Z2,addr,kind` packet, or of a Z3, Z4 or z2..z4 one
+fn watch_packet_len(packet: &[u8]) -> Option<u64> {
+    let packet = std::str::from_utf8(packet).ok()?;
+    let mut fields = packet.split(',');
+    match fields.next()? {
+        "Z2" | "Z3" | "Z4" | "z2" | "z3" | "z4" => {}
+        _ => return None,
     }
+    fields.next()?;
+    u64::from_str_radix(fields.next()?, 16).ok()
 }
 
-pub struct BPFArch;
-
-impl Arch for BPFArch {
-    type Usize = u64;
-    type Registers = BPFRegs;
-    type RegId = BPFRegId;
-}
+impl<C: Connection> Connection for WatchedConnection<C> {
+    type Error = C::Error;
 
-impl Target for DebugServer {
-    type Arch = BPFArch;
-    type Error = &'static str;
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        let result = self.inner.read();
+        if let Ok(byte) = result {
+            self.scan(byte);
+        }
+        self.watch(result)
+    }
 
-    fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        let result = self.inner.write(byte);
+        self.watch(result)
     }
 
-    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<Self>> {
-        Some(self)
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        let result = self.inner.peek();
+        self.watch(result)
     }
 
-    fn section_offsets(&mut self) -> Option<SectionOffsetsOps<Self>> {
-        Some(self)
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        let result = self.inner.flush();
+        self.watch(result)
     }
 }
 
-pub enum VmRequest {
-    Resume,
-    Interrupt,
-    Step,
-    ReadReg(u8),
-    ReadRegs,
-    WriteReg(u8, u64),
-    WriteRegs([u64; 12]),
-    ReadMem(u64, u64),
-    WriteMem(u64, u64, Vec<u8>),
-    SetBrkpt(u64),
-    RemoveBrkpt(u64),
-    Offsets,
-    Detatch,
+fn listen_for_gdb(port: u16) -> std::io::Result<TcpListener> {
+    TcpListener::bind(format!("localhost:{}", port))
 }
 
-pub enum VmReply {
-    DoneStep,
-    Interrupt,
-    Halted,
-    Breakpoint,
-    Err(&'static str),
-    ReadRegs([u64; 12]),
-    ReadReg(u64),
-    WriteRegs,
-    WriteReg,
-    ReadMem(Vec<u8>),
-    WriteMem,
-    SetBrkpt,
-    RemoveBrkpt,
-    Offsets(Offsets<u64>),
+fn accept_gdb_connection(sock: &TcpListener) -> std::io::Result<TcpStream> {
+    eprintln!(
+        "Waiting for a GDB connection on {:?}...",
+        sock.local_addr()?
+    );
+    let (stream, addr) = sock.accept()?;
+
+    // Blocks until a GDB client connects via TCP.
+    // i.e: Running `target remote localhost:<port>` from the GDB prompt.
+
+    eprintln!("Debugger connected from {}", addr);
+    Ok(stream)
 }
 
-// TODO make this not use unwrap
-impl SingleThreadOps for DebugServer {
-    fn resume(
-        &mut self,
-        action: ResumeAction,
-        check_gdb_interrupt: &mut dyn FnMut() -> bool,
-    ) -> Result<StopReason<u64>, Self::Error> {
-        match action {
-            ResumeAction::Step => {
-                self.req.send(VmRequest::Step).unwrap();
-                match self.reply.recv().unwrap() {
-                    VmReply::DoneStep => Ok(StopReason::DoneStep),
-                    _ => Err("unexpected  from VM"),
-                }
+#[cfg(unix)]
+fn listen_for_gdb_unix(path: &Path) -> std::io::Result<UnixListener> {
+    UnixListener::bind(path)
+}
+
+#[cfg(unix)]
+fn accept_gdb_unix_connection(sock: &UnixListener, path: &Path) -> std::io::Result<UnixStream> {
+    eprintln!("Waiting for a GDB connection on {:?}...", path);
+    let (stream, _) = sock.accept()?;
+
+    // Blocks until a GDB client connects via the socket.
+    // i.e: Running `target remote <path>` from the GDB prompt, or through a relay like socat.
+
+    eprintln!("Debugger connected on {:?}", path);
+    Ok(stream)
+}
+
+#[cfg(unix)]
+pub struct SerialConnection {
+    port: File,
+    peeked: Option<u8>,
+}
+
+#[cfg(unix)]
+impl SerialConnection {
+    /// Opens the serial device and puts the line into raw mode at the given baud rate
+    pub fn open(path: &Path, baud: u32) -> std::io::Result<Self> {
+        let speed = match baud {
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unsupported baud rate {}", baud),
+                ))
             }
-            ResumeAction::Continue => {
-                self.req.send(VmRequest::Resume).unwrap();
-                // TODO find a better way to deal with check_gdb_interrupt
-                while !check_gdb_interrupt() {
-                    if let Ok(event) = self.reply.try_recv() {
-                        return match event {
-                            VmReply::Breakpoint => Ok(StopReason::SwBreak),
-                            VmReply::Halted => Ok(StopReason::Halted),
-                            VmReply::Err(e) => Err(e),
-                            _ => Err("unexpected reply from VM"),
-                        };
-                    }
-                }
-                self.req.send(VmRequest::Interrupt).unwrap();
-                match self.reply.recv().unwrap() {
-                    VmReply::Interrupt => Ok(StopReason::GdbInterrupt),
-                    VmReply::Err(e) => Err(e),
-                    _ => Err("unexpected reply from VM"),
-                }
+        };
+        let port = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+        let fd = port.as_raw_fd();
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            if libc::cfsetispeed(&mut termios, speed) != 0
+                || libc::cfsetospeed(&mut termios, speed) != 0
+                || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+            {
+                return Err(std::io::Error::last_os_error());
             }
         }
+        eprintln!("Waiting for GDB on serial device {:?}...", path);
+        Ok(SerialConnection { port, peeked: None })
     }
+}
 
-    fn read_registers(&mut self, regs: &mut BPFRegs) -> TargetResult<(), Self> {
-        self.req.send(VmRequest::ReadRegs).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::ReadRegs(regfile) => {
-                *regs = unsafe { std::mem::transmute_copy(&regfile) };
-                Ok(())
-            }
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+#[cfg(unix)]
+impl Connection for SerialConnection {
+    type Error = std::io::Error;
+
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
         }
+        let mut buf = [0u8; 1];
+        self.port.read_exact(&mut buf)?;
+        Ok(buf[0])
     }
 
-    fn write_registers(&mut self, regs: &BPFRegs) -> TargetResult<(), Self> {
-        let regfile: [u64; 12] = unsafe { std::mem::transmute_copy(regs) };
-        self.req.send(VmRequest::WriteRegs(regfile)).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::WriteRegs => Ok(()),
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
-        }
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.port.write_all(&[byte])
     }
 
-    fn read_register(&mut self, reg_id: BPFRegId, dst: &mut [u8]) -> TargetResult<(), Self> {
-        self.req.send(VmRequest::ReadReg(reg_id.into())).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::ReadReg(val) => {
-                dst.copy_from_slice(&val.to_le_bytes());
-                Ok(())
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        if self.peeked.is_none() {
+            let mut pollfd = libc::pollfd {
+                fd: self.port.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // must not block, gdbstub polls this for interrupts while the target runs
+            if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if pollfd.revents & libc::POLLIN != 0 {
+                let mut buf = [0u8; 1];
+                self.port.read_exact(&mut buf)?;
+                self.peeked = Some(buf[0]);
             }
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
         }
+        Ok(self.peeked)
     }
 
-    fn write_register(&mut self, reg_id: BPFRegId, val: &[u8]) -> TargetResult<(), Self> {
-        let mut rdr = Cursor::new(val);
-        match rdr.read_u64::<LittleEndian>() {
-            Ok(reg) => {
-                self.req
-                    .send(VmRequest::WriteReg(reg_id.into(), reg))
-                    .unwrap();
-                match self.reply.recv().unwrap() {
-                    VmReply::WriteReg => Ok(()),
-                    VmReply::Err(e) => Err(TargetError::Fatal(e)),
-                    _ => Err(TargetError::Fatal("unexpected reply from VM")),
-                }
-            }
-            _ => Err(TargetError::Fatal("invalid number of bytes")),
-        }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.port.flush()
     }
+}
 
-    fn read_addrs(&mut self, start_addr: u64, dst: &mut [u8]) -> TargetResult<(), Self> {
-        self.req
-            .send(VmRequest::ReadMem(start_addr, dst.len() as u64))
-            .unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::ReadMem(bytes) => {
-                debug_assert!(
-                    bytes.len() == dst.len(),
-                    "vm returned wrong number of bytes!"
-                );
-                dst.copy_from_slice(&bytes[..]);
-                Ok(())
-            }
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
-        }
+/// `DebugTransport::Connection`, gdbstub only implements Connection for boxes that
+/// aren't Send
+struct BoxedConnection(Box<dyn Connection<Error = std::io::Error> + Send>);
+
+impl Connection for BoxedConnection {
+    type Error = std::io::Error;
+
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        self.0.read()
     }
 
-    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
-        self.req
-            .send(VmRequest::WriteMem(
-                start_addr,
-                data.len() as u64,
-                data.to_vec(),
-            ))
-            .unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::WriteMem => Ok(()),
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
-        }
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.0.write(byte)
     }
-}
 
-// TODO make this not use unwrap
-impl SwBreakpoint for DebugServer {
-    fn add_sw_breakpoint(&mut self, addr: u64) -> TargetResult<bool, Self> {
-        self.req.send(VmRequest::SetBrkpt(addr)).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::SetBrkpt => Ok(true),
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
-        }
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        self.0.peek()
     }
 
-    fn remove_sw_breakpoint(&mut self, addr: u64) -> TargetResult<bool, Self> {
-        self.req.send(VmRequest::RemoveBrkpt(addr)).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::RemoveBrkpt => Ok(true),
-            VmReply::Err(e) => Err(TargetError::Fatal(e)),
-            _ => Err(TargetError::Fatal("unexpected reply from VM")),
-        }
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush()
+    }
+
+    fn on_session_start(&mut self) -> Result<(), Self::Error> {
+        self.0.on_session_start()
     }
 }
 
-// TODO make this not use unwrap
-impl SectionOffsets for DebugServer {
-    fn get_section_offsets(&mut self) -> Result<Offsets<u64>, Self::Error> {
-        self.req.send(VmRequest::Offsets).unwrap();
-        match self.reply.recv().unwrap() {
-            VmReply::Offsets(offsets) => Ok(offsets),
-            VmReply::Err(e) => Err(e),
-            _ => Err("unexpected reply from VM"),
-        }
+/// One end of an in-process byte pipe, lets a GDB session run without binding a socket
+pub struct PipeConnection {
+    tx: mpsc::Sender<u8>,
+    rx: mpsc::Receiver<u8>,
+    peeked: Option<u8>,
+}
+
+impl PipeConnection {
+    /// Both ends of the pipe, hand one to the debug server and talk RSP over the other
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (
+            PipeConnection {
+                tx: a_tx,
+                rx: a_rx,
+                peeked: None,
+            },
+            PipeConnection {
+                tx: b_tx,
+                rx: b_rx,
+                peeked: None,
+            },
+        )
+    }
+
+    /// Sends `data` framed as `$data#XX`
+    pub fn send_packet(&mut self, data: &[u8]) -> std::io::Result<()> {
+        for byte in frame_packet(data) {
+            self.write(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the next packet, skipping acks, and returns its payload once the
+    /// checksum is verified
+    pub fn recv_packet(&mut self) -> std::io::Result<Vec<u8>> {
+        while self.read()? != b'$' {}
+        let mut data = Vec::new();
+        loop {
+            match self.read()? {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        let digits = [self.read()?, self.read()?];
+        let checksum = std::str::from_utf8(&digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+        if checksum != Some(packet_checksum(&data)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "bad packet checksum",
+            ));
+        }
+        Ok(data)
+    }
+}
+
+impl Connection for PipeConnection {
+    type Error = std::io::Error;
+
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
+        }
+        self.rx
+            .recv()
+            .map_err(|_| std::io::ErrorKind::UnexpectedEof.into())
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.tx
+            .send(byte)
+            .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        if self.peeked.is_none() {
+            match self.rx.try_recv() {
+                Ok(byte) => self.peeked = Some(byte),
+                Err(mpsc::TryRecvError::Empty) => {}
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into())
+                }
+            }
+        }
+        Ok(self.peeked)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Frames an RSP packet as `$data#XX` with the checksum GDB expects
+pub fn frame_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(format!("#{:02x}", packet_checksum(data)).as_bytes());
+    packet
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CondOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A register compared against an immediate, e.g. `r1 == 0x10`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreakpointCondition {
+    pub reg: u8,
+    pub op: CondOp,
+    pub imm: u64,
+}
+
+impl BreakpointCondition {
+    pub fn parse(cond: &[&str]) -> Option<Self> {
+        let (reg, op, imm) = match cond {
+            [reg, op, imm] => (reg, op, imm),
+            _ => return None,
+        };
+        let reg = reg.strip_prefix('r')?.parse::<u8>().ok()?;
+        if reg as usize >= NUM_REGS {
+            return None;
+        }
+        let op = match *op {
+            "==" => CondOp::Eq,
+            "!=" => CondOp::Ne,
+            "<" => CondOp::Lt,
+            "<=" => CondOp::Le,
+            ">" => CondOp::Gt,
+            ">=" => CondOp::Ge,
+            _ => return None,
+        };
+        let imm = parse_u64(imm)?;
+        Some(BreakpointCondition { reg, op, imm })
+    }
+
+    pub fn eval(&self, regs: &[u64; NUM_REGS]) -> bool {
+        let val = regs[self.reg as usize];
+        match self.op {
+            CondOp::Eq => val == self.imm,
+            CondOp::Ne => val != self.imm,
+            CondOp::Lt => val < self.imm,
+            CondOp::Le => val <= self.imm,
+            CondOp::Gt => val > self.imm,
+            CondOp::Ge => val >= self.imm,
+        }
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hex number
+fn parse_u64(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// A helper is named either by its symbol hash or by its symbol name
+fn parse_helper_id(s: &str) -> u32 {
+    match parse_u64(s) {
+        Some(id) if id <= u32::MAX as u64 => id as u32,
+        _ => ebpf::hash_symbol_name(s.as_bytes()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    /// assigned in the order breakpoints are set, starting at 1
+    pub number: u32,
+    /// a disabled breakpoint stays in the table but never stops or counts hits
+    pub enabled: bool,
+    /// cleared by GDB's `z0`, which it also sends to step off a breakpoint, so the
+    /// number, condition and counts outlive it until the breakpoint is deleted
+    pub inserted: bool,
+    pub condition: Option<BreakpointCondition>,
+    /// removed after the first hit
+    pub temporary: bool,
+    pub hit_count: u64,
+    /// number of upcoming hits to skip before stopping
+    pub ignore_count: u64,
+}
+
+impl Default for Breakpoint {
+    fn default() -> Self {
+        Breakpoint {
+            number: 0,
+            enabled: true,
+            inserted: true,
+            condition: None,
+            temporary: false,
+            hit_count: 0,
+            ignore_count: 0,
+        }
+    }
+}
+
+pub enum BreakpointEntries {
+    /// kept sorted by address so lookups can binary search
+    Few(Vec<(u64, Breakpoint)>),
+    Many(HashMap<u64, Breakpoint>),
+}
+
+/// The breakpoints GDB has set, keyed by instruction index
+pub struct BreakpointTable {
+    /// number of breakpoints above which lookups switch to a hash map
+    thresh: usize,
+    entries: BreakpointEntries,
+    next_number: u32,
+}
+
+impl Default for BreakpointTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BreakpointTable {
+    /// Creates an empty table that switches to a hash map above 30 breakpoints
+    pub fn new() -> Self {
+        Self::with_threshold(BRPKT_MAP_THRESH)
+    }
+
+    /// Creates an empty table that switches to a hash map above `thresh` breakpoints
+    pub fn with_threshold(thresh: usize) -> Self {
+        BreakpointTable {
+            thresh,
+            entries: BreakpointEntries::Few(Vec::new()),
+            next_number: 1,
+        }
+    }
+
+    /// Returns the breakpoint at `addr`, if any
+    pub fn get_breakpoint_mut(&mut self, addr: u64) -> Option<&mut Breakpoint> {
+        match self.entries {
+            BreakpointEntries::Few(ref mut brkpts) => {
+                match brkpts.binary_search_by_key(&addr, |(brkpt_addr, _)| *brkpt_addr) {
+                    Ok(i) => Some(&mut brkpts[i].1),
+                    Err(_) => None,
+                }
+            }
+            BreakpointEntries::Many(ref mut brkpts) => brkpts.get_mut(&addr),
+        }
+    }
+
+    /// Returns true if there is a breakpoint at `addr`
+    pub fn check_breakpoint(&self, addr: u64) -> bool {
+        match &self.entries {
+            BreakpointEntries::Few(brkpts) => brkpts
+                .binary_search_by_key(&addr, |(brkpt_addr, _)| *brkpt_addr)
+                .is_ok(),
+            BreakpointEntries::Many(brkpts) => brkpts.contains_key(&addr),
+        }
+    }
+
+    /// Returns true if execution should stop at `addr` given the current registers,
+    /// a temporary breakpoint that stops is removed
+    pub fn hit_breakpoint(&mut self, addr: u64, regs: &[u64; NUM_REGS]) -> bool {
+        let (stop, temporary) = match self.get_breakpoint_mut(addr) {
+            Some(brkpt) => {
+                if !brkpt.inserted
+                    || !brkpt.enabled
+                    || brkpt.condition.is_some_and(|cond| !cond.eval(regs))
+                {
+                    return false;
+                }
+                brkpt.hit_count += 1;
+                if brkpt.ignore_count > 0 {
+                    brkpt.ignore_count -= 1;
+                    (false, brkpt.temporary)
+                } else {
+                    (true, brkpt.temporary)
+                }
+            }
+            None => return false,
+        };
+        if stop && temporary {
+            self.remove_breakpoint(addr);
+        }
+        stop
+    }
+
+    /// Sets a breakpoint at `addr`, or inserts the one already there again
+    pub fn set_breakpoint(&mut self, addr: u64) {
+        // a permanent breakpoint takes over a temporary one at the same address
+        if let Some(brkpt) = self.get_breakpoint_mut(addr) {
+            brkpt.temporary = false;
+            brkpt.inserted = true;
+            return;
+        }
+        self.insert(addr, Breakpoint::default());
+    }
+
+    /// Sets a breakpoint at `addr` that is removed when it stops execution
+    pub fn set_temporary_breakpoint(&mut self, addr: u64) {
+        // never downgrade an existing permanent breakpoint
+        if let Some(brkpt) = self.get_breakpoint_mut(addr) {
+            brkpt.inserted = true;
+            return;
+        }
+        self.insert(
+            addr,
+            Breakpoint {
+                temporary: true,
+                ..Breakpoint::default()
+            },
+        );
+    }
+
+    fn insert(&mut self, addr: u64, mut brkpt: Breakpoint) {
+        brkpt.number = self.next_number;
+        self.next_number += 1;
+        match self.entries {
+            BreakpointEntries::Few(ref mut brkpts) => {
+                if brkpts.len() > self.thresh {
+                    let mut map = HashMap::with_capacity(brkpts.len() + 1);
+                    map.insert(addr, brkpt);
+                    map.extend(brkpts.drain(..));
+                    self.entries = BreakpointEntries::Many(map);
+                } else {
+                    match brkpts.binary_search_by_key(&addr, |(brkpt_addr, _)| *brkpt_addr) {
+                        Ok(i) => brkpts[i].1 = brkpt,
+                        Err(i) => brkpts.insert(i, (addr, brkpt)),
+                    }
+                }
+            }
+            BreakpointEntries::Many(ref mut brkpts) => {
+                brkpts.insert(addr, brkpt);
+            }
+        }
+    }
+
+    /// Attaches a condition to the breakpoint at `addr`, returns false if there is none
+    pub fn set_condition(&mut self, addr: u64, condition: Option<BreakpointCondition>) -> bool {
+        match self.get_breakpoint_mut(addr) {
+            Some(brkpt) => {
+                brkpt.condition = condition;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Enables or disables the breakpoint at `addr`, returns false if there is none
+    pub fn set_enabled(&mut self, addr: u64, enabled: bool) -> bool {
+        match self.get_breakpoint_mut(addr) {
+            Some(brkpt) => {
+                brkpt.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets how many hits of the breakpoint at `addr` to skip, returns false if there is none
+    pub fn set_ignore_count(&mut self, addr: u64, count: u64) -> bool {
+        match self.get_breakpoint_mut(addr) {
+            Some(brkpt) => {
+                brkpt.ignore_count = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns every breakpoint sorted by address
+    pub fn breakpoints(&self) -> Vec<(u64, Breakpoint)> {
+        match &self.entries {
+            BreakpointEntries::Few(brkpts) => brkpts.clone(),
+            BreakpointEntries::Many(brkpts) => {
+                let mut sorted: Vec<(u64, Breakpoint)> = brkpts
+                    .iter()
+                    .map(|(addr, brkpt)| (*addr, brkpt.clone()))
+                    .collect();
+                sorted.sort_by_key(|(addr, _)| *addr);
+                sorted
+            }
+        }
+    }
+
+    /// Takes the breakpoint at `addr` out of execution but keeps everything known about
+    /// it, returns false if there is no inserted breakpoint there
+    pub fn lift_breakpoint(&mut self, addr: u64) -> bool {
+        match self.get_breakpoint_mut(addr) {
+            Some(brkpt) if brkpt.inserted => {
+                brkpt.inserted = false;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Deletes the breakpoint at `addr` along with its condition and counts,
+    /// returns the number it had
+    pub fn remove_breakpoint(&mut self, addr: u64) -> Option<u32> {
+        match self.entries {
+            BreakpointEntries::Few(ref mut brkpts) => {
+                match brkpts.binary_search_by_key(&addr, |(brkpt_addr, _)| *brkpt_addr) {
+                    Ok(i) => Some(brkpts.remove(i).1.number),
+                    Err(_) => None,
+                }
+            }
+            BreakpointEntries::Many(ref mut brkpts) => {
+                let removed = brkpts.remove(&addr).map(|brkpt| brkpt.number);
+                // only demote well below the promotion threshold to avoid thrashing
+                if brkpts.len() < self.thresh / 2 {
+                    let mut few: Vec<_> = brkpts.drain().collect();
+                    few.sort_by_key(|(brkpt_addr, _)| *brkpt_addr);
+                    self.entries = BreakpointEntries::Few(few);
+                }
+                removed
+            }
+        }
+    }
+}
+
+/// Watched ranges by address, length and kind
+pub struct WatchpointTable(Vec<(u64, u64, WatchKind)>);
+
+impl WatchpointTable {
+    pub fn new() -> Self {
+        WatchpointTable(Vec::new())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn set_watchpoint(&mut self, addr: u64, len: u64, kind: WatchKind) {
+        if !self.0.contains(&(addr, len, kind)) {
+            self.0.push((addr, len, kind));
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u64, len: u64, kind: WatchKind) -> bool {
+        if let Some(i) = self.0.iter().position(|watch| *watch == (addr, len, kind)) {
+            self.0.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the first watchpoint overlapping an access of `len` bytes at `addr`
+    pub fn check_access(&self, addr: u64, len: u64, is_store: bool) -> Option<(u64, WatchKind)> {
+        self.0
+            .iter()
+            .find(|(watch_addr, watch_len, kind)| {
+                let kind_matches = match kind {
+                    WatchKind::Write => is_store,
+                    WatchKind::Read => !is_store,
+                    WatchKind::ReadWrite => true,
+                };
+                kind_matches
+                    && addr < watch_addr.saturating_add(*watch_len)
+                    && *watch_addr < addr.saturating_add(len)
+            })
+            .map(|(watch_addr, _, kind)| (*watch_addr, *kind))
+    }
+}
+
+/// Helpers the VM stops at, by their symbol hash. An empty list catches every helper
+#[derive(Debug, Default)]
+pub struct CatchpointTable(Option<Vec<u32>>);
+
+impl CatchpointTable {
+    pub fn new() -> Self {
+        CatchpointTable(None)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn set_catchpoints(&mut self, mut ids: Vec<u32>) {
+        ids.sort_unstable();
+        ids.dedup();
+        self.0 = Some(ids);
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = None;
+    }
+
+    pub fn catchpoints(&self) -> Option<&[u32]> {
+        self.0.as_deref()
+    }
+
+    pub fn catches(&self, id: u32) -> bool {
+        match &self.0 {
+            Some(ids) => ids.is_empty() || ids.binary_search(&id).is_ok(),
+            None => false,
+        }
+    }
+}
+
+pub struct DebugInterface {
+    pub reply: mpsc::SyncSender<VmReply>,
+    pub req: mpsc::Receiver<VmRequest>,
+    /// Owned by the VM thread. The server, in any session, only changes breakpoints
+    /// through requests the VM serves between instructions, so the check on every
+    /// instruction needs no lock
+    pub breakpoints: BreakpointTable,
+    pub watchpoints: WatchpointTable,
+    pub pending_watch: Option<(u64, WatchKind)>,
+    pub catchpoints: CatchpointTable,
+    /// a caught helper has returned, reported before the next instruction runs
+    pub pending_catch: Option<u32>,
+    /// the helper and whether it was entered or returned from, if that is why the VM stopped
+    pub caught: Option<(u32, bool)>,
+    pub step: bool,
+    /// GDB assumes the target is stopped when it attaches, so hold before the first
+    /// instruction until it resumes instead of running ahead while it queries the state
+    pub stop_at_entry: bool,
+    /// the debugger went away, the VM should run on without it
+    pub detached: bool,
+    /// the debugger asked to terminate execution
+    pub killed: bool,
+    /// where the VM stopped has been sent since it was last resumed
+    pub stop_reported: bool,
+    /// the debugger asked to run the program again from its entry point
+    pub restart: bool,
+    /// the program has stopped for good, only its final state can be inspected
+    pub finished: bool,
+    /// how many instructions the VM runs before stopping, set by `monitor set maxinsns`
+    pub insn_budget: Option<u64>,
+    /// what is left of the budget until the next stop
+    pub insns_left: u64,
+    pub snapshots: HashMap<u32, VmSnapshot>,
+    next_snapshot_id: u32,
+}
+
+/// Registers, call frames and writable memory captured by `monitor checkpoint`
+pub struct VmSnapshot {
+    pub regs: [u64; NUM_REGS_WITH_PC],
+    pub frames: Option<CallFramesState>,
+    /// address and contents of every writable region
+    pub memory: Vec<(u64, Vec<u8>)>,
+}
+
+impl VmSnapshot {
+    fn size(&self) -> usize {
+        self.memory.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+impl DebugInterface {
+    pub fn new(channels: DebugChannels) -> Self {
+        Self::with_brkpt_threshold(channels, BRPKT_MAP_THRESH)
+    }
+
+    pub fn with_brkpt_threshold((reply, req): DebugChannels, brkpt_threshold: usize) -> Self {
+        DebugInterface {
+            reply,
+            req,
+            breakpoints: BreakpointTable::with_threshold(brkpt_threshold),
+            watchpoints: WatchpointTable::new(),
+            pending_watch: None,
+            catchpoints: CatchpointTable::new(),
+            pending_catch: None,
+            caught: None,
+            step: false,
+            stop_at_entry: true,
+            detached: false,
+            killed: false,
+            stop_reported: false,
+            restart: false,
+            finished: false,
+            insn_budget: None,
+            insns_left: 0,
+            snapshots: HashMap::new(),
+            next_snapshot_id: 0,
+        }
+    }
+    /// Captures the state of `vm`, evicting the oldest checkpoints to stay in budget
+    pub fn checkpoint<V: DebuggableVm>(&mut self, vm: &V) -> Result<u32, &'static str> {
+        let mut memory = Vec::new();
+        for (addr, len) in vm.writable_regions() {
+            memory.push((addr, vm.read_mem(addr, len)?));
+        }
+        let snapshot = VmSnapshot {
+            regs: vm.read_regs(),
+            frames: vm.save_frames(),
+            memory,
+        };
+        if snapshot.size() > MAX_SNAPSHOT_BYTES {
+            return Err("VM memory is too large to checkpoint");
+        }
+        let mut total: usize = self.snapshots.values().map(VmSnapshot::size).sum();
+        while total + snapshot.size() > MAX_SNAPSHOT_BYTES {
+            let oldest = *self.snapshots.keys().min().unwrap();
+            total -= self.snapshots.remove(&oldest).unwrap().size();
+        }
+        let id = self.next_snapshot_id;
+        self.next_snapshot_id += 1;
+        self.snapshots.insert(id, snapshot);
+        Ok(id)
+    }
+
+    /// Puts `vm` back into the state captured by checkpoint `id`, which stays available
+    pub fn restore<V: DebuggableVm>(&mut self, vm: &mut V, id: u32) -> Result<(), &'static str> {
+        let snapshot = self.snapshots.get(&id).ok_or("no such checkpoint")?;
+        vm.write_regs(&snapshot.regs)?;
+        if let Some(frames) = snapshot.frames.as_ref() {
+            vm.restore_frames(frames);
+        }
+        for (addr, bytes) in snapshot.memory.iter() {
+            vm.write_mem(*addr, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Handles a single request from the debug server against `vm`,
+    /// returns true if the VM should resume execution
+    pub fn handle_request<V: DebuggableVm>(&mut self, vm: &mut V, request: VmRequest) -> bool {
+        let reply = match request {
+            VmRequest::Resume => {
+                self.stop_reported = false;
+                self.caught = None;
+                return true;
+            }
+            VmRequest::Step => {
+                self.stop_reported = false;
+                self.caught = None;
+                self.step = true;
+                return true;
+            }
+            VmRequest::Detach => {
+                self.detached = true;
+                return true;
+            }
+            VmRequest::Kill => {
+                self.killed = true;
+                return true;
+            }
+            VmRequest::Restart if self.finished => VmReply::Err("the program has finished"),
+            VmRequest::Restart => {
+                // the run loop resets the VM and stops again at the entry point
+                self.restart = true;
+                self.stop_at_entry = true;
+                self.step = false;
+                self.pending_watch = None;
+                self.pending_catch = None;
+                self.insns_left = self.insn_budget.unwrap_or(0);
+                self.send_reply(VmReply::Restart);
+                return true;
+            }
+            // the stop already sent answers the interrupt
+            VmRequest::Interrupt if self.stop_reported => return false,
+            VmRequest::Interrupt => VmReply::Interrupt,
+            VmRequest::ReadRegs => VmReply::ReadRegs(vm.read_regs()),
+            VmRequest::ReadReg(id) => match vm.read_regs().get(id as usize) {
+                Some(val) => VmReply::ReadReg(*val),
+                None => VmReply::Err("invalid register"),
+            },
+            VmRequest::WriteReg(id, val) => match vm.write_reg(id, val) {
+                Ok(()) => VmReply::WriteReg,
+                Err(e) => VmReply::Err(e),
+            },
+            VmRequest::WriteRegs(regs) => match vm.write_regs(&regs) {
+                Ok(()) => VmReply::WriteRegs,
+                Err(e) => VmReply::Err(e),
+            },
+            VmRequest::ReadMem(addr, len) => match vm.read_mem(addr, len) {
+                Ok(bytes) => VmReply::ReadMem(bytes),
+                Err(e) => VmReply::Err(e),
+            },
+            VmRequest::WriteMem(addr, len, bytes) => {
+                if bytes.len() as u64 != len {
+                    VmReply::Err("invalid number of bytes")
+                } else if len == 0 {
+                    VmReply::WriteMem
+                } else {
+                    match vm.write_mem(addr, &bytes) {
+                        Ok(()) => VmReply::WriteMem,
+                        Err(e) => VmReply::Err(e),
+                    }
+                }
+            }
+            VmRequest::SetBrkpt(addr) => match vm.breakpoint_key(addr) {
+                Some(key) => {
+                    self.breakpoints.set_breakpoint(key);
+                    VmReply::SetBrkpt
+                }
+                None => VmReply::Err("invalid breakpoint address"),
+            },
+            VmRequest::SetTempBrkpt(addr) => match vm.breakpoint_key(addr) {
+                Some(key) => {
+                    self.breakpoints.set_temporary_breakpoint(key);
+                    VmReply::SetBrkpt
+                }
+                None => VmReply::Err("invalid breakpoint address"),
+            },
+            VmRequest::SetBrkptCond(addr, condition) => match vm.breakpoint_key(addr) {
+                Some(key) if self.breakpoints.set_condition(key, condition) => {
+                    VmReply::SetBrkptCond
+                }
+                _ => VmReply::Err("no breakpoint at that address"),
+            },
+            VmRequest::SetIgnoreCount(addr, count) => match vm.breakpoint_key(addr) {
+                Some(key) if self.breakpoints.set_ignore_count(key, count) => {
+                    VmReply::SetIgnoreCount
+                }
+                _ => VmReply::Err("no breakpoint at that address"),
+            },
+            VmRequest::SetBrkptEnabled(addr, enabled) => match vm.breakpoint_key(addr) {
+                Some(key) if self.breakpoints.set_enabled(key, enabled) => VmReply::SetBrkptEnabled,
+                _ => VmReply::Err("no breakpoint at that address"),
+            },
+            VmRequest::ListBrkpts => VmReply::Brkpts(
+                self.breakpoints
+                    .breakpoints()
+                    .into_iter()
+                    .map(|(key, brkpt)| (vm.breakpoint_addr(key), brkpt))
+                    .collect(),
+            ),
+            VmRequest::RemoveBrkpt(addr) => match vm.breakpoint_key(addr) {
+                Some(key) if self.breakpoints.lift_breakpoint(key) => VmReply::RemoveBrkpt,
+                _ => VmReply::Err("no breakpoint at that address"),
+            },
+            VmRequest::DeleteBrkpt(addr) => match vm.breakpoint_key(addr) {
+                Some(key) if self.breakpoints.remove_breakpoint(key).is_some() => {
+                    VmReply::DeleteBrkpt
+                }
+                _ => VmReply::Err("no breakpoint at that address"),
+            },
+            VmRequest::SetWatchpt(addr, len, kind) => {
+                self.watchpoints.set_watchpoint(addr, len, kind);
+                VmReply::SetWatchpt
+            }
+            VmRequest::RemoveWatchpt(addr, len, kind) => {
+                self.watchpoints.remove_watchpoint(addr, len, kind);
+                VmReply::RemoveWatchpt
+            }
+            VmRequest::SetCatchpoints(Some(ids)) => {
+                self.catchpoints.set_catchpoints(ids);
+                VmReply::SetCatchpoints
+            }
+            VmRequest::SetCatchpoints(None) => {
+                self.catchpoints.clear();
+                VmReply::SetCatchpoints
+            }
+            VmRequest::ListCatchpoints => VmReply::Catchpoints(
+                self.catchpoints.catchpoints().map(<[u32]>::to_vec),
+                self.caught,
+            ),
+            VmRequest::SetInsnBudget(budget) => {
+                self.insn_budget = budget;
+                self.insns_left = budget.unwrap_or(0);
+                VmReply::SetInsnBudget
+            }
+            VmRequest::InsnBudget => {
+                VmReply::InsnBudget(self.insn_budget.map(|budget| (budget, self.insns_left)))
+            }
+            VmRequest::Offsets => {
+                let (text, data, bss) = vm.section_offsets();
+                VmReply::Offsets(text, data, bss)
+            }
+            VmRequest::Backtrace => VmReply::Backtrace(vm.backtrace()),
+            VmRequest::Disassemble(addr, count) => {
+                let addr = addr.unwrap_or_else(|| vm.read_regs()[NUM_REGS]);
+                match disassemble_text(vm.text(), addr, count) {
+                    Some(insns) => VmReply::Disassembly(insns),
+                    None => VmReply::Err("address is not an instruction in the program"),
+                }
+            }
+            VmRequest::Checkpoint => match self.checkpoint(vm) {
+                Ok(id) => VmReply::Checkpoint(id),
+                Err(e) => VmReply::Err(e),
+            },
+            VmRequest::Restore(id) => match self.restore(vm, id) {
+                Ok(()) => VmReply::Restore,
+                Err(e) => VmReply::Err(e),
+            },
+        };
+        !self.send_reply(reply)
+    }
+
+    /// Sends a reply or stop event to the debug server, if the server has gone away the
+    /// VM is detached and false is returned
+    pub fn send_reply(&mut self, reply: VmReply) -> bool {
+        if let VmReply::DoneStep
+        | VmReply::Interrupt
+        | VmReply::Breakpoint
+        | VmReply::Watchpoint { .. }
+        | VmReply::SyscallEntry(_)
+        | VmReply::SyscallReturn(_)
+        | VmReply::BudgetExhausted
+        | VmReply::Signal(_) = reply
+        {
+            self.stop_reported = true;
+        }
+        match reply {
+            VmReply::SyscallEntry(id) => self.caught = Some((id, true)),
+            VmReply::SyscallReturn(id) => self.caught = Some((id, false)),
+            _ => {}
+        }
+        if self.reply.send(reply).is_err() {
+            debug!("debugger detached from VM");
+            self.detached = true;
+            return false;
+        }
+        true
+    }
+
+    /// Polls the debug server for a request, if `block` is set or the request stops the VM
+    /// keeps serving requests until the debugger resumes execution
+    pub fn check_for_request<V: DebuggableVm>(&mut self, vm: &mut V, block: bool) {
+        if !block {
+            match self.req.try_recv() {
+                Ok(request) => {
+                    if self.handle_request(vm, request) {
+                        return;
+                    }
+                }
+                Err(mpsc::TryRecvError::Empty) => return,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    debug!("debugger detached from VM");
+                    self.detached = true;
+                    return;
+                }
+            }
+        }
+        loop {
+            if let Ok(request) = self.req.recv() {
+                if self.handle_request(vm, request) {
+                    return;
+                }
+            } else {
+                debug!("debugger detached from VM");
+                self.detached = true;
+                return;
+            }
+        }
+    }
+}
+
+/// The execution engine side of a debugging session. DebugInterface turns the requests
+/// from the debug server into calls on this trait, so any interpreter implementing it
+/// can be debugged. Stepping and continuing stay with the engine's run loop, which
+/// polls DebugInterface between instructions.
+pub trait DebuggableVm {
+    /// r0..r10 followed by the pc as a debugger address
+    fn read_regs(&self) -> [u64; NUM_REGS_WITH_PC];
+
+    /// Register `NUM_REGS` is the pc as a debugger address
+    fn write_reg(&mut self, id: u8, val: u64) -> Result<(), &'static str>;
+
+    fn write_regs(&mut self, regs: &[u64; NUM_REGS_WITH_PC]) -> Result<(), &'static str> {
+        // the pc is the only register that can be rejected, check it before writing any
+        self.write_reg(NUM_REGS as u8, regs[NUM_REGS])?;
+        for (id, val) in regs[..NUM_REGS].iter().enumerate() {
+            self.write_reg(id as u8, *val)?;
+        }
+        Ok(())
+    }
+
+    /// A read running past the end of the readable memory returns only the part before it,
+    /// an error means not even the first byte is readable
+    fn read_mem(&self, addr: u64, len: u64) -> Result<Vec<u8>, &'static str>;
+
+    /// Only writes to memory the program itself could write to
+    fn write_mem(&mut self, addr: u64, bytes: &[u8]) -> Result<(), &'static str>;
+
+    /// Converts a debugger address into the key breakpoints are stored under,
+    /// the one the run loop checks against
+    fn breakpoint_key(&self, addr: u64) -> Option<u64>;
+
+    /// Converts a breakpoint key back into a debugger address
+    fn breakpoint_addr(&self, key: u64) -> u64;
+
+    /// How far the text, data and bss sections were moved from their ELF addresses
+    fn section_offsets(&self) -> (u64, u64, Option<u64>);
+
+    /// Address and length of every region the program can write to
+    fn writable_regions(&self) -> Vec<(u64, u64)>;
+
+    /// The pc and frame base of every active call frame, innermost first
+    fn backtrace(&self) -> Vec<(u64, u64)>;
+
+    /// The call frames as of now, None if the VM doesn't keep any
+    fn save_frames(&self) -> Option<CallFramesState>;
+
+    /// Puts back the call frames returned by `save_frames`
+    fn restore_frames(&mut self, frames: &CallFramesState);
+
+    /// Debugger address and bytes of the program text
+    fn text(&self) -> (u64, &[u8]);
+}
+
+/// Disassembles up to `count` instructions starting at `addr`, stopping at the end of the
+/// text. Returns None if `addr` is not an instruction in the text.
+fn disassemble_text(
+    (text_addr, text): (u64, &[u8]),
+    addr: u64,
+    count: usize,
+) -> Option<Vec<(u64, String)>> {
+    let start = addr.checked_sub(text_addr)? as usize;
+    if !start.is_multiple_of(ebpf::INSN_SIZE) || start >= text.len() {
+        return None;
+    }
+    // find where the last instruction ends, lddw takes up two slots
+    let mut end = start;
+    for _ in 0..count {
+        let len = if text[end] == ebpf::LD_DW_IMM {
+            2 * ebpf::INSN_SIZE
+        } else {
+            ebpf::INSN_SIZE
+        };
+        if end + len > text.len() {
+            break;
+        }
+        end += len;
+        if end == text.len() {
+            break;
+        }
+    }
+    Some(
+        disassembler::to_insn_vec(&text[start..end])
+            .into_iter()
+            .map(|insn| (addr + (insn.ptr * ebpf::INSN_SIZE) as u64, insn.desc))
+            .collect(),
+    )
+}
+
+pub struct DebugServer {
+    req: mpsc::SyncSender<VmRequest>,
+    reply: mpsc::Receiver<VmReply>,
+    regs: BPFRegs,
+    reply_timeout: Duration,
+    /// the last request timed out and the VM still owes its reply
+    reply_pending: bool,
+    short_read_policy: ShortReadPolicy,
+    mem_cache: Option<MemCache>,
+    max_write_size: Option<usize>,
+    /// the GDB connection of the current session failed
+    conn_lost: Arc<AtomicBool>,
+    /// length of the last Z2/Z3/Z4 or z2/z3/z4 packet, gdbstub 0.4 doesn't pass it on
+    watch_len: Arc<AtomicU64>,
+}
+
+impl DebugServer {
+    fn new(
+        regs: &[u64; NUM_REGS],
+        pc: u64,
+        reply_timeout: Duration,
+    ) -> (Self, mpsc::SyncSender<VmReply>, mpsc::Receiver<VmRequest>) {
+        let (req_tx, req_rx) = mpsc::sync_channel::<VmRequest>(0);
+        // A stop event is queued rather than handed over, so a VM stopping on its own never
+        // blocks against an interrupt the server is sending at the same time
+        let (reply_tx, reply_rx) = mpsc::sync_channel::<VmReply>(1);
+        (
+            DebugServer {
+                req: req_tx,
+                reply: reply_rx,
+                regs: BPFRegs {
+                    regs: *regs,
+                    pc: pc,
+                    unavailable: 0,
+                },
+                reply_timeout,
+                reply_pending: false,
+                short_read_policy: ShortReadPolicy::Error,
+                mem_cache: None,
+                max_write_size: None,
+                conn_lost: Arc::new(AtomicBool::new(false)),
+                watch_len: Arc::new(AtomicU64::new(0)),
+            },
+            reply_tx,
+            req_rx,
+        )
+    }
+
+    /// Takes the length of the watchpoint packet being handled, a single byte if
+    /// the packet did not come through a session's connection
+    fn watch_len(&self) -> u64 {
+        self.watch_len.swap(0, Ordering::Relaxed).max(1)
+    }
+
+    /// Sends a request to the stopped VM and waits for its reply
+    fn request(&mut self, req: VmRequest) -> Result<VmReply, DebugError> {
+        self.send_request(req)?;
+        self.recv_reply()
+    }
+
+    fn send_request(&mut self, req: VmRequest) -> Result<(), DebugError> {
+        if self.reply_pending {
+            // otherwise the late reply would be taken as the answer to this request
+            self.recv_reply()?;
+        }
+        match req {
+            VmRequest::ReadMem(..) | VmRequest::ReadRegs | VmRequest::ReadReg(_) => {}
+            // anything else may run the VM or change its memory
+            _ => {
+                if let Some(cache) = self.mem_cache.as_mut() {
+                    cache.clear();
+                }
+            }
+        }
+        trace_request(&req);
+        self.req.send(req)?;
+        Ok(())
+    }
+
+    /// Serves a read that falls within a single page from the cache, fetching the page
+    /// first if needed. Anything else, or a read past what the VM could read of the page,
+    /// is left to a direct request
+    fn cached_read(&mut self, addr: u64, len: usize) -> Option<&[u8]> {
+        let page = addr & !(CACHE_PAGE_SIZE - 1);
+        let offset = (addr - page) as usize;
+        if self.mem_cache.is_none() || offset + len > CACHE_PAGE_SIZE as usize {
+            return None;
+        }
+        if self.mem_cache.as_ref()?.get(page).is_none() {
+            let bytes = match self.request(VmRequest::ReadMem(page, CACHE_PAGE_SIZE)) {
+                Ok(VmReply::ReadMem(bytes)) => bytes,
+                _ => return None,
+            };
+            self.mem_cache.as_mut()?.insert(page, bytes);
+        }
+        self.mem_cache
+            .as_ref()?
+            .get(page)?
+            .get(offset..offset + len)
+    }
+
+    fn recv_reply(&mut self) -> Result<VmReply, DebugError> {
+        match self.reply.recv_timeout(self.reply_timeout) {
+            Ok(reply) => {
+                trace_reply(&reply);
+                self.reply_pending = false;
+                Ok(reply)
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                warn!("VM did not reply within {:?}", self.reply_timeout);
+                self.reply_pending = true;
+                Err(DebugError::Timeout(self.reply_timeout))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(DebugError::ChannelClosed),
+        }
+    }
+
+    /// Lets the VM run on without the debugger, a VM that already finished is ignored
+    fn detach(&mut self) {
+        let _ = self.req.send(VmRequest::Detach);
+    }
+
+    /// Terminates the VM's execution, a VM that already finished is ignored
+    fn kill(&mut self) {
+        let _ = self.req.send(VmRequest::Kill);
+    }
+}
+
+/// Pages of VM memory read while the VM is stopped, the oldest is dropped first
+struct MemCache {
+    pages: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>,
+    max_pages: usize,
+}
+
+impl MemCache {
+    fn new(max_pages: usize) -> Self {
+        MemCache {
+            pages: HashMap::new(),
+            order: VecDeque::new(),
+            max_pages,
+        }
+    }
+
+    fn get(&self, page: u64) -> Option<&Vec<u8>> {
+        self.pages.get(&page)
+    }
+
+    fn insert(&mut self, page: u64, bytes: Vec<u8>) {
+        if self.max_pages == 0 {
+            return;
+        }
+        while self.order.len() >= self.max_pages {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pages.remove(&oldest);
+            }
+        }
+        self.order.push_back(page);
+        self.pages.insert(page, bytes);
+    }
+
+    fn clear(&mut self) {
+        self.pages.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BPFRegs {
+    regs: [u64; NUM_REGS],
+    pc: u64,
+    /// bit `i` marks register `i` as unavailable, bit `NUM_REGS` the pc
+    unavailable: u16,
+}
+
+impl BPFRegs {
+    /// A register file the VM hasn't produced, every register reads as unavailable
+    fn unavailable() -> Self {
+        BPFRegs {
+            unavailable: (1 << NUM_REGS_WITH_PC) - 1,
+            ..BPFRegs::default()
+        }
+    }
+
+    /// Register `NUM_REGS` is the pc
+    fn is_available(&self, id: usize) -> bool {
+        self.unavailable & (1 << id) == 0
+    }
+}
+
+impl From<[u64; NUM_REGS_WITH_PC]> for BPFRegs {
+    fn from(regfile: [u64; NUM_REGS_WITH_PC]) -> BPFRegs {
+        let mut regs = [0u64; NUM_REGS];
+        regs.copy_from_slice(&regfile[0..NUM_REGS]);
+        BPFRegs {
+            regs,
+            pc: regfile[NUM_REGS],
+            unavailable: 0,
+        }
+    }
+}
+
+impl From<&BPFRegs> for [u64; NUM_REGS_WITH_PC] {
+    fn from(regs: &BPFRegs) -> [u64; NUM_REGS_WITH_PC] {
+        let mut regfile = [0u64; NUM_REGS_WITH_PC];
+        regfile[0..NUM_REGS].copy_from_slice(&regs.regs);
+        regfile[NUM_REGS] = regs.pc;
+        regfile
+    }
+}
+
+impl Registers for BPFRegs {
+    fn gdb_serialize(&self, mut write_byte: impl FnMut(Option<u8>)) {
+        // GDB reads the registers positionally: r0..r10, then pc
+        for (id, reg) in self
+            .regs
+            .iter()
+            .chain(std::iter::once(&self.pc))
+            .enumerate()
+        {
+            // unavailable registers go out as `xx` so GDB doesn't show them as zero
+            let available = self.is_available(id);
+            reg.to_le_bytes()
+                .iter()
+                .for_each(|b| write_byte(if available { Some(*b) } else { None }));
+        }
+    }
+
+    fn gdb_deserialize(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        if bytes.len() != REG_WITH_PC_NUM_BYTES {
+            return Err(());
+        }
+        let mut regfile = [0u64; NUM_REGS_WITH_PC];
+        for (reg, chunk) in regfile.iter_mut().zip(bytes.chunks_exact(REG_SIZE)) {
+            let mut buf = [0u8; REG_SIZE];
+            buf.copy_from_slice(chunk);
+            *reg = u64::from_le_bytes(buf);
+        }
+        *self = BPFRegs::from(regfile);
+        Ok(())
+    }
+}
+
+/// Decodes a value sent by GDB for `reg`, which has to be as wide as the register.
+/// A `w` register value is zero-extended
+fn reg_from_le_bytes(reg: BpfReg, val: &[u8]) -> Option<u64> {
+    if reg.is_sub_reg() {
+        val.try_into()
+            .ok()
+            .map(|val| u32::from_le_bytes(val) as u64)
+    } else {
+        val.try_into().ok().map(u64::from_le_bytes)
+    }
+}
+
+/// The registers GDB knows about, in the order it numbers them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BpfReg {
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9,
+    R10,
+    Pc,
+    /// low 32 bits of r0 to r10, as ALU32 instructions see them
+    W0,
+    W1,
+    W2,
+    W3,
+    W4,
+    W5,
+    W6,
+    W7,
+    W8,
+    W9,
+    W10,
+}
+
+impl BpfReg {
+    pub const ALL: [BpfReg; NUM_REGS_WITH_PC] = [
+        BpfReg::R0,
+        BpfReg::R1,
+        BpfReg::R2,
+        BpfReg::R3,
+        BpfReg::R4,
+        BpfReg::R5,
+        BpfReg::R6,
+        BpfReg::R7,
+        BpfReg::R8,
+        BpfReg::R9,
+        BpfReg::R10,
+        BpfReg::Pc,
+    ];
+
+    /// Only read and written one at a time, they are not part of the `g` packet
+    pub const SUB_REGS: [BpfReg; NUM_REGS] = [
+        BpfReg::W0,
+        BpfReg::W1,
+        BpfReg::W2,
+        BpfReg::W3,
+        BpfReg::W4,
+        BpfReg::W5,
+        BpfReg::W6,
+        BpfReg::W7,
+        BpfReg::W8,
+        BpfReg::W9,
+        BpfReg::W10,
+    ];
+
+    pub fn name(self) -> &'static str {
+        [
+            "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "pc", "w0", "w1",
+            "w2", "w3", "w4", "w5", "w6", "w7", "w8", "w9", "w10",
+        ][self as usize]
+    }
+
+    pub fn is_sub_reg(self) -> bool {
+        self as usize > BpfReg::Pc as usize
+    }
+
+    /// The 64-bit register holding this one, which is itself unless it is a `w` register
+    pub fn full_reg(self) -> BpfReg {
+        if self.is_sub_reg() {
+            BpfReg::ALL[self as usize - BpfReg::W0 as usize]
+        } else {
+            self
+        }
+    }
+
+    /// The pc and the `w` registers have no DWARF register number
+    pub fn dwarf_number(self) -> Option<u16> {
+        match self {
+            BpfReg::Pc => None,
+            reg if reg.is_sub_reg() => None,
+            reg => Some(reg as u16),
+        }
+    }
+}
+
+impl RegId for BpfReg {
+    fn from_raw_id(id: usize) -> Option<(Self, usize)> {
+        match id.checked_sub(NUM_REGS_WITH_PC) {
+            None => Some((BpfReg::ALL[id], REG_SIZE)),
+            Some(id) => BpfReg::SUB_REGS.get(id).map(|reg| (*reg, REG_SIZE / 2)),
+        }
+    }
+}
+
+impl From<BpfReg> for u8 {
+    fn from(reg: BpfReg) -> u8 {
+        reg as u8
+    }
+}
+
+// Register order has to match BpfReg and BPFRegs::gdb_serialize. GDB fetches the
+// registers past the end of the `g` packet, the `w` ones, with `p`
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.bpf.core">
+    <reg name="r0" bitsize="64" type="int64" regnum="0"/>
+    <reg name="r1" bitsize="64" type="int64"/>
+    <reg name="r2" bitsize="64" type="int64"/>
+    <reg name="r3" bitsize="64" type="int64"/>
+    <reg name="r4" bitsize="64" type="int64"/>
+    <reg name="r5" bitsize="64" type="int64"/>
+    <reg name="r6" bitsize="64" type="int64"/>
+    <reg name="r7" bitsize="64" type="int64"/>
+    <reg name="r8" bitsize="64" type="int64"/>
+    <reg name="r9" bitsize="64" type="int64"/>
+    <reg name="r10" bitsize="64" type="data_ptr"/>
+    <reg name="pc" bitsize="64" type="code_ptr"/>
+  </feature>
+  <feature name="org.gnu.gdb.bpf.alu32">
+    <reg name="w0" bitsize="32" type="int32" regnum="12"/>
+    <reg name="w1" bitsize="32" type="int32"/>
+    <reg name="w2" bitsize="32" type="int32"/>
+    <reg name="w3" bitsize="32" type="int32"/>
+    <reg name="w4" bitsize="32" type="int32"/>
+    <reg name="w5" bitsize="32" type="int32"/>
+    <reg name="w6" bitsize="32" type="int32"/>
+    <reg name="w7" bitsize="32" type="int32"/>
+    <reg name="w8" bitsize="32" type="int32"/>
+    <reg name="w9" bitsize="32" type="int32"/>
+    <reg name="w10" bitsize="32" type="int32"/>
+  </feature>
+</target>"#;
+
+pub struct BPFArch;
+
+impl Arch for BPFArch {
+    type Usize = u64;
+    type Registers = BPFRegs;
+    type RegId = BpfReg;
+
+    fn target_description_xml() -> Option<&'static str> {
+        Some(TARGET_XML)
+    }
+}
+
+impl Target for DebugServer {
+    type Arch = BPFArch;
+    type Error = &'static str;
+
+    fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+        BaseOps::SingleThread(self)
+    }
+
+    fn sw_breakpoint(&mut self) -> Option<SwBreakpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn hw_watchpoint(&mut self) -> Option<HwWatchpointOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn section_offsets(&mut self) -> Option<SectionOffsetsOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn monitor_cmd(&mut self) -> Option<MonitorCmdOps<'_, Self>> {
+        Some(self)
+    }
+
+    fn extended_mode(&mut self) -> Option<ExtendedModeOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum VmRequest {
+    Resume,
+    Interrupt,
+    Step,
+    ReadReg(u8),
+    ReadRegs,
+    WriteReg(u8, u64),
+    WriteRegs([u64; NUM_REGS_WITH_PC]),
+    ReadMem(u64, u64),
+    WriteMem(u64, u64, Vec<u8>),
+    SetBrkpt(u64),
+    SetTempBrkpt(u64),
+    SetBrkptCond(u64, Option<BreakpointCondition>),
+    SetIgnoreCount(u64, u64),
+    SetBrkptEnabled(u64, bool),
+    ListBrkpts,
+    /// GDB's `z0`, the breakpoint is kept for when GDB inserts it again
+    RemoveBrkpt(u64),
+    DeleteBrkpt(u64),
+    /// address, length and kind of the watched range
+    SetWatchpt(u64, u64, WatchKind),
+    RemoveWatchpt(u64, u64, WatchKind),
+    /// `None` clears every catchpoint, an empty list catches every helper
+    SetCatchpoints(Option<Vec<u32>>),
+    ListCatchpoints,
+    SetInsnBudget(Option<u64>),
+    InsnBudget,
+    Offsets,
+    Backtrace,
+    Disassemble(Option<u64>, usize),
+    Checkpoint,
+    Restore(u32),
+    Detach,
+    Kill,
+    Restart,
+}
+
+#[derive(Debug)]
+pub enum VmReply {
+    DoneStep,
+    Interrupt,
+    Exited(u8),
+    Signal(u8),
+    Terminated(u8),
+    Breakpoint,
+    Watchpoint {
+        addr: u64,
+        kind: WatchKind,
+    },
+    SyscallEntry(u32),
+    SyscallReturn(u32),
+    BudgetExhausted,
+    Err(&'static str),
+    ReadRegs([u64; NUM_REGS_WITH_PC]),
+    ReadReg(u64),
+    WriteRegs,
+    WriteReg,
+    ReadMem(Vec<u8>),
+    WriteMem,
+    SetBrkpt,
+    SetBrkptCond,
+    SetIgnoreCount,
+    SetBrkptEnabled,
+    Brkpts(Vec<(u64, Breakpoint)>),
+    RemoveBrkpt,
+    DeleteBrkpt,
+    SetWatchpt,
+    RemoveWatchpt,
+    SetCatchpoints,
+    Catchpoints(Option<Vec<u32>>, Option<(u32, bool)>),
+    SetInsnBudget,
+    /// the budget and how much of it is left
+    InsnBudget(Option<(u64, u64)>),
+    /// text, data and bss offsets, gdbstub's Offsets has no Debug
+    Offsets(u64, u64, Option<u64>),
+    Backtrace(Vec<(u64, u64)>),
+    Disassembly(Vec<(u64, String)>),
+    Checkpoint(u32),
+    Restore,
+    Restart,
+}
+
+/// Number of bytes of a memory request or reply shown before it is cut short
+const SUMMARY_BYTES: usize = 16;
+
+/// Hex of the first few bytes, followed by the total length if there are more
+fn bytes_summary(bytes: &[u8]) -> String {
+    let hex: String = bytes
+        .iter()
+        .take(SUMMARY_BYTES)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if bytes.len() > SUMMARY_BYTES {
+        format!("{}..({} bytes)", hex, bytes.len())
+    } else {
+        hex
+    }
+}
+
+impl std::fmt::Display for VmRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmRequest::ReadReg(id) => write!(f, "ReadReg{{id={}}}", id),
+            VmRequest::WriteReg(id, val) => write!(f, "WriteReg{{id={},val={:#x}}}", id, val),
+            VmRequest::ReadMem(addr, len) => write!(f, "ReadMem{{addr={:#x},len={}}}", addr, len),
+            VmRequest::WriteMem(addr, len, bytes) => write!(
+                f,
+                "WriteMem{{addr={:#x},len={},bytes={}}}",
+                addr,
+                len,
+                bytes_summary(bytes)
+            ),
+            VmRequest::SetBrkpt(addr) => write!(f, "SetBrkpt{{addr={:#x}}}", addr),
+            VmRequest::SetTempBrkpt(addr) => write!(f, "SetTempBrkpt{{addr={:#x}}}", addr),
+            VmRequest::RemoveBrkpt(addr) => write!(f, "RemoveBrkpt{{addr={:#x}}}", addr),
+            req => write!(f, "{:?}", req),
+        }
+    }
+}
+
+impl std::fmt::Display for VmReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmReply::ReadReg(val) => write!(f, "ReadReg{{val={:#x}}}", val),
+            VmReply::ReadMem(bytes) => write!(
+                f,
+                "ReadMem{{len={},bytes={}}}",
+                bytes.len(),
+                bytes_summary(bytes)
+            ),
+            VmReply::Watchpoint { addr, kind } => {
+                write!(f, "Watchpoint{{addr={:#x},kind={:?}}}", addr, kind)
+            }
+            reply => write!(f, "{:?}", reply),
+        }
+    }
+}
+
+// gdbstub logs the raw RSP packets itself, also at trace level
+
+fn trace_request(req: &VmRequest) {
+    match req {
+        VmRequest::WriteMem(addr, _, bytes)
+            if log_enabled!(Level::Trace) && bytes.len() > SUMMARY_BYTES =>
+        {
+            trace!("request {}\n{}", req, hexdump(*addr, bytes))
+        }
+        req => trace!("request {}", req),
+    }
+}
+
+fn trace_reply(reply: &VmReply) {
+    match reply {
+        VmReply::ReadMem(bytes) if log_enabled!(Level::Trace) && bytes.len() > SUMMARY_BYTES => {
+            trace!("reply {}\n{}", reply, hexdump(0, bytes))
+        }
+        reply => trace!("reply {}", reply),
+    }
+}
+
+/// Formats `bytes` 16 to a line as address, hex and ASCII columns
+fn hexdump(addr: u64, bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            format!(
+                "{:#018x}  {:<47}  |{}|",
+                addr + i as u64 * 16,
+                hex.join(" "),
+                ascii
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Maps the VM's report of where it stopped to what GDB is told
+fn stop_reason(reply: VmReply) -> Result<StopReason<u64>, &'static str> {
+    match reply {
+        // gdbstub sends a plain S05 for a step and T05swbreak:; for a breakpoint, which
+        // tells GDB whether it has to step off a breakpoint before resuming
+        VmReply::DoneStep => Ok(StopReason::DoneStep),
+        VmReply::Interrupt => Ok(StopReason::GdbInterrupt),
+        VmReply::Breakpoint => Ok(StopReason::SwBreak),
+        VmReply::Watchpoint { addr, kind } => Ok(StopReason::Watch { kind, addr }),
+        // gdbstub 0.4 has no syscall stop, `monitor catch` tells which helper it was
+        VmReply::SyscallEntry(_) | VmReply::SyscallReturn(_) => Ok(StopReason::Signal(SIGTRAP)),
+        // GDB shows SIGXCPU, `monitor show maxinsns` the budget
+        VmReply::BudgetExhausted => Ok(StopReason::Signal(SIGXCPU)),
+        // gdbstub 0.4 can only report a halt (W19), the embedder gets r0 from the run itself
+        VmReply::Exited(code) => {
+            info!("program exited with status {}", code);
+            Ok(StopReason::Halted)
+        }
+        VmReply::Signal(sig) => Ok(StopReason::Signal(sig)),
+        // no StopReason::Terminated in gdbstub 0.4, the fault is reported as a signal again
+        VmReply::Terminated(sig) => Ok(StopReason::Signal(sig)),
+        VmReply::Err(e) => Err(e),
+        _ => Err("unexpected reply from VM"),
+    }
+}
+
+impl SingleThreadOps for DebugServer {
+    fn resume(
+        &mut self,
+        action: ResumeAction,
+        check_gdb_interrupt: &mut dyn FnMut() -> bool,
+    ) -> Result<StopReason<u64>, Self::Error> {
+        match action {
+            ResumeAction::Step => {
+                stop_reason(self.request(VmRequest::Step).map_err(|e| e.as_str())?)
+            }
+            ResumeAction::Continue => {
+                self.send_request(VmRequest::Resume)
+                    .map_err(|e| e.as_str())?;
+                // block on the VM and only look at GDB on each tick, an idle Continue
+                // then costs next to no CPU
+                loop {
+                    match self.reply.recv_timeout(RESUME_POLL_INTERVAL) {
+                        Ok(event) => {
+                            trace_reply(&event);
+                            return stop_reason(event);
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            return Err(DebugError::ChannelClosed.as_str())
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    }
+                    // a dropped connection stops the VM as well, the session then ends
+                    // when gdbstub fails to send the stop
+                    if check_gdb_interrupt() || self.conn_lost.load(Ordering::Relaxed) {
+                        break;
+                    }
+                }
+                // The VM answers with wherever it actually stopped. If it stopped on its
+                // own before it saw the interrupt, that stop was already queued and is what
+                // comes back, the interrupt itself then goes unanswered
+                let reply = match self.request(VmRequest::Interrupt) {
+                    // the VM may have exited just after queueing its last stop
+                    Err(DebugError::ChannelClosed) => self
+                        .reply
+                        .try_recv()
+                        .map_err(|_| DebugError::ChannelClosed.as_str())?,
+                    reply => reply.map_err(|e| e.as_str())?,
+                };
+                stop_reason(reply)
+            }
+        }
+    }
+
+    fn read_registers(&mut self, regs: &mut BPFRegs) -> TargetResult<(), Self> {
+        let reply = match self.request(VmRequest::ReadRegs) {
+            Ok(reply) => reply,
+            // the VM is still busy, e.g. in a long syscall, and has no registers to show yet
+            Err(DebugError::Timeout(_)) => {
+                *regs = BPFRegs::unavailable();
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match reply {
+            VmReply::ReadRegs(regfile) => {
+                *regs = BPFRegs::from(regfile);
+                Ok(())
+            }
+            VmReply::Err(e) => Err(TargetError::Fatal(e)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn write_registers(&mut self, regs: &BPFRegs) -> TargetResult<(), Self> {
+        let regfile = <[u64; NUM_REGS_WITH_PC]>::from(regs);
+        match self.request(VmRequest::WriteRegs(regfile))? {
+            VmReply::WriteRegs => Ok(()),
+            VmReply::Err(e) => Err(TargetError::Fatal(e)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn read_register(&mut self, reg_id: BpfReg, dst: &mut [u8]) -> TargetResult<(), Self> {
+        match self.request(VmRequest::ReadReg(reg_id.full_reg().into()))? {
+            VmReply::ReadReg(val) if reg_id.is_sub_reg() => {
+                dst.copy_from_slice(&(val as u32).to_le_bytes());
+                Ok(())
+            }
+            VmReply::ReadReg(val) => {
+                dst.copy_from_slice(&val.to_le_bytes());
+                Ok(())
+            }
+            VmReply::Err(e) => Err(TargetError::Fatal(e)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn write_register(&mut self, reg_id: BpfReg, val: &[u8]) -> TargetResult<(), Self> {
+        // a `w` register is zero-extended into its `r` register, as an ALU32 result is
+        let reg = reg_from_le_bytes(reg_id, val).ok_or(TargetError::NonFatal)?;
+        match self.request(VmRequest::WriteReg(reg_id.full_reg().into(), reg))? {
+            VmReply::WriteReg => Ok(()),
+            // e.g. a pc outside the program, GDB reports the write as failed
+            VmReply::Err(_) => Err(TargetError::NonFatal),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn read_addrs(&mut self, start_addr: u64, dst: &mut [u8]) -> TargetResult<(), Self> {
+        if let Some(bytes) = self.cached_read(start_addr, dst.len()) {
+            dst.copy_from_slice(bytes);
+            return Ok(());
+        }
+        match self.request(VmRequest::ReadMem(start_addr, dst.len() as u64))? {
+            VmReply::Err(_) => Err(TargetError::Errno(0)),
+            VmReply::ReadMem(bytes) if bytes.len() > dst.len() => {
+                Err(TargetError::Fatal("VM returned too many bytes"))
+            }
+            VmReply::ReadMem(bytes) => {
+                let (read, rest) = dst.split_at_mut(bytes.len());
+                read.copy_from_slice(&bytes);
+                match self.short_read_policy {
+                    _ if rest.is_empty() => Ok(()),
+                    ShortReadPolicy::Error => Err(TargetError::Errno(0)),
+                    ShortReadPolicy::ZeroFill => {
+                        rest.iter_mut().for_each(|b| *b = 0);
+                        Ok(())
+                    }
+                }
+            }
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+        if matches!(self.max_write_size, Some(max) if data.len() > max) {
+            return Err(TargetError::Errno(2));
+        }
+        match self.request(VmRequest::WriteMem(
+            start_addr,
+            data.len() as u64,
+            data.to_vec(),
+        ))? {
+            VmReply::WriteMem => Ok(()),
+            // E01, e.g. for a write to read-only memory
+            VmReply::Err(_) => Err(TargetError::Errno(1)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+}
+
+impl SwBreakpoint for DebugServer {
+    fn add_sw_breakpoint(&mut self, addr: u64) -> TargetResult<bool, Self> {
+        match self.request(VmRequest::SetBrkpt(addr))? {
+            VmReply::SetBrkpt => Ok(true),
+            // the address is not an instruction of the program
+            VmReply::Err(_) => Ok(false),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn remove_sw_breakpoint(&mut self, addr: u64) -> TargetResult<bool, Self> {
+        match self.request(VmRequest::RemoveBrkpt(addr))? {
+            VmReply::RemoveBrkpt => Ok(true),
+            VmReply::Err(_) => Ok(false),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+}
+
+impl HwWatchpoint for DebugServer {
+    fn add_hw_watchpoint(&mut self, addr: u64, kind: WatchKind) -> TargetResult<bool, Self> {
+        let len = self.watch_len();
+        match self.request(VmRequest::SetWatchpt(addr, len, kind))? {
+            VmReply::SetWatchpt => Ok(true),
+            VmReply::Err(e) => Err(TargetError::Fatal(e)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+
+    fn remove_hw_watchpoint(&mut self, addr: u64, kind: WatchKind) -> TargetResult<bool, Self> {
+        let len = self.watch_len();
+        match self.request(VmRequest::RemoveWatchpt(addr, len, kind))? {
+            VmReply::RemoveWatchpt => Ok(true),
+            VmReply::Err(e) => Err(TargetError::Fatal(e)),
+            _ => Err(TargetError::Fatal("unexpected reply from VM")),
+        }
+    }
+}
+
+impl SectionOffsets for DebugServer {
+    fn get_section_offsets(&mut self) -> Result<Offsets<u64>, Self::Error> {
+        match self.request(VmRequest::Offsets).map_err(|e| e.as_str())? {
+            VmReply::Offsets(text, data, bss) => Ok(Offsets::Sections { text, data, bss }),
+            VmReply::Err(e) => Err(e),
+            _ => Err("unexpected reply from VM"),
+        }
+    }
+}
+
+// `!` is answered by gdbstub, which only passes `R` and `vRun` on once it was sent
+impl ExtendedMode for DebugServer {
+    fn run(&mut self, _filename: Option<&[u8]>, _args: Args) -> TargetResult<Pid, Self> {
+        // there is only the one program, running it again restarts it
+        match ExtendedMode::restart(self) {
+            Ok(()) => Ok(VM_PID),
+            // GDB reports the run as failed and the session stays up
+            Err(e) => {
+                warn!("Could not run the program again: {}", e);
+                Err(TargetError::NonFatal)
+            }
+        }
+    }
+
+    fn attach(&mut self, _pid: Pid) -> TargetResult<(), Self> {
+        Err(TargetError::NonFatal)
+    }
+
+    fn kill(&mut self, pid: Option<Pid>) -> TargetResult<ShouldTerminate, Self> {
+        if pid.is_some_and(|pid| pid != VM_PID) {
+            return Err(TargetError::NonFatal);
+        }
+        // the VM is torn down when the session ends with DisconnectReason::Kill
+        Ok(ShouldTerminate::Yes)
+    }
+
+    /// Only works while the program is stopped, once it exited the VM no longer
+    /// serves the debugger and there is nothing left to restart
+    fn restart(&mut self) -> Result<(), Self::Error> {
+        match self.request(VmRequest::Restart) {
+            Ok(VmReply::Restart) => Ok(()),
+            Ok(VmReply::Err(e)) => Err(e),
+            Ok(_) => Err("unexpected reply from VM"),
+            Err(DebugError::ChannelClosed) => Err("the program has exited and can't be restarted"),
+            Err(e) => Err(e.as_str()),
+        }
+    }
+}
+
+impl MonitorCmd for DebugServer {
+    fn handle_monitor_cmd(
+        &mut self,
+        cmd: &[u8],
+        mut out: ConsoleOutput<'_>,
+    ) -> Result<(), Self::Error> {
+        let cmd = match std::str::from_utf8(cmd) {
+            Ok(cmd) => cmd.trim(),
+            Err(_) => {
+                outputln!(out, "command must be valid UTF-8");
+                return Ok(());
+            }
+        };
+        match cmd.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["regs"] => {
+                let mut regs = BPFRegs::default();
+                if self.read_registers(&mut regs).is_err() {
+                    outputln!(out, "could not read registers from VM");
+                    return Ok(());
+                }
+                for (i, reg) in regs.regs.iter().enumerate() {
+                    if regs.is_available(i) {
+                        outputln!(out, "r{:<3} {:#018x}", i, reg);
+                    } else {
+                        outputln!(out, "r{:<3} <unavailable>", i);
+                    }
+                }
+                if regs.is_available(NUM_REGS) {
+                    outputln!(out, "pc   {:#018x}", regs.pc);
+                } else {
+                    outputln!(out, "pc   <unavailable>");
+                }
+            }
+            ["tbreak", addr] => {
+                let addr = match parse_u64(addr) {
+                    Some(addr) => addr,
+                    None => {
+                        outputln!(out, "invalid breakpoint address '{}'", addr);
+                        return Ok(());
+                    }
+                };
+                match self
+                    .request(VmRequest::SetTempBrkpt(addr))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetBrkpt => {}
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["ignore", addr, count] => {
+                let (addr, count) = match (parse_u64(addr), parse_u64(count)) {
+                    (Some(addr), Some(count)) => (addr, count),
+                    _ => {
+                        outputln!(out, "usage: monitor ignore <addr> <count>");
+                        return Ok(());
+                    }
+                };
+                match self
+                    .request(VmRequest::SetIgnoreCount(addr, count))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetIgnoreCount => {}
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["breakpoints"] | ["info", "break"] => {
+                let mut brkpts = match self
+                    .request(VmRequest::ListBrkpts)
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::Brkpts(brkpts) => brkpts,
+                    _ => return Err("unexpected reply from VM"),
+                };
+                brkpts.sort_by_key(|(_, brkpt)| brkpt.number);
+                outputln!(
+                    out,
+                    "{:<5} {:<18} {:<3} {:>8} {:>8}",
+                    "num",
+                    "address",
+                    "enb",
+                    "hits",
+                    "ignore"
+                );
+                for (addr, brkpt) in brkpts {
+                    outputln!(
+                        out,
+                        "{:<5} {:#018x} {:<3} {:>8} {:>8}{}{}",
+                        brkpt.number,
+                        addr,
+                        if brkpt.enabled { "y" } else { "n" },
+                        brkpt.hit_count,
+                        brkpt.ignore_count,
+                        if brkpt.temporary { " (temporary)" } else { "" },
+                        if brkpt.inserted {
+                            ""
+                        } else {
+                            " (removed by GDB)"
+                        }
+                    );
+                }
+            }
+            ["delete", addr] => {
+                let addr = match parse_u64(addr) {
+                    Some(addr) => addr,
+                    None => {
+                        outputln!(out, "invalid breakpoint address '{}'", addr);
+                        return Ok(());
+                    }
+                };
+                match self
+                    .request(VmRequest::DeleteBrkpt(addr))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::DeleteBrkpt => {}
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            [command @ "enable", addr] | [command @ "disable", addr] => {
+                let addr = match parse_u64(addr) {
+                    Some(addr) => addr,
+                    None => {
+                        outputln!(out, "invalid breakpoint address '{}'", addr);
+                        return Ok(());
+                    }
+                };
+                match self
+                    .request(VmRequest::SetBrkptEnabled(addr, *command == "enable"))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetBrkptEnabled => {}
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["bt"] => match self.request(VmRequest::Backtrace).map_err(|e| e.as_str())? {
+                VmReply::Backtrace(frames) => {
+                    for (i, (pc, frame)) in frames.iter().enumerate() {
+                        outputln!(out, "#{:<3} pc {:#018x} frame {:#018x}", i, pc, frame);
+                    }
+                }
+                _ => return Err("unexpected reply from VM"),
+            },
+            ["disas", args @ ..] if args.len() <= 2 => {
+                let addr = match args.first().map(|addr| parse_u64(addr)) {
+                    Some(None) => {
+                        outputln!(out, "invalid address '{}'", args[0]);
+                        return Ok(());
+                    }
+                    addr => addr.flatten(),
+                };
+                let count = match args.get(1).map(|count| count.parse()) {
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => {
+                        outputln!(out, "invalid count '{}'", args[1]);
+                        return Ok(());
+                    }
+                    None => DISAS_DEFAULT_COUNT,
+                };
+                match self
+                    .request(VmRequest::Disassemble(addr, count))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::Disassembly(insns) => {
+                        for (addr, desc) in insns {
+                            outputln!(out, "{:#018x}  {}", addr, desc);
+                        }
+                    }
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            // gdbstub 0.4 doesn't handle QCatchSyscalls, so `catch syscall` in GDB itself
+            // can't reach the VM
+            ["catch"] => {
+                let (ids, caught) = match self
+                    .request(VmRequest::ListCatchpoints)
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::Catchpoints(ids, caught) => (ids, caught),
+                    _ => return Err("unexpected reply from VM"),
+                };
+                match ids {
+                    None => outputln!(out, "no helper catchpoints"),
+                    Some(ids) if ids.is_empty() => outputln!(out, "catching every helper"),
+                    Some(ids) => {
+                        for id in ids {
+                            outputln!(out, "catching helper {:#010x}", id);
+                        }
+                    }
+                }
+                match caught {
+                    Some((id, true)) => outputln!(out, "stopped entering helper {:#010x}", id),
+                    Some((id, false)) => {
+                        outputln!(out, "stopped returning from helper {:#010x}", id)
+                    }
+                    None => {}
+                }
+            }
+            ["catch", "call", ids @ ..] => {
+                let ids = ids.iter().map(|id| parse_helper_id(id)).collect();
+                match self
+                    .request(VmRequest::SetCatchpoints(Some(ids)))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetCatchpoints => {}
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["catch", "off"] => {
+                match self
+                    .request(VmRequest::SetCatchpoints(None))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetCatchpoints => {}
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["set", "maxinsns", budget] => {
+                let budget = match *budget {
+                    "off" => None,
+                    budget => match parse_u64(budget) {
+                        Some(0) => None,
+                        Some(budget) => Some(budget),
+                        None => {
+                            outputln!(out, "expected an instruction count or 'off'");
+                            return Ok(());
+                        }
+                    },
+                };
+                match self
+                    .request(VmRequest::SetInsnBudget(budget))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetInsnBudget => {}
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["show", "maxinsns"] => {
+                match self
+                    .request(VmRequest::InsnBudget)
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::InsnBudget(Some((budget, left))) => {
+                        outputln!(out, "{} of {} instructions left", left, budget)
+                    }
+                    VmReply::InsnBudget(None) => outputln!(out, "no instruction budget"),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["checkpoint"] => {
+                match self
+                    .request(VmRequest::Checkpoint)
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::Checkpoint(id) => outputln!(out, "checkpoint {}", id),
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["restore", id] => {
+                let id = match id.parse() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        outputln!(out, "invalid checkpoint id '{}'", id);
+                        return Ok(());
+                    }
+                };
+                match self
+                    .request(VmRequest::Restore(id))
+                    .map_err(|e| e.as_str())?
+                {
+                    // GDB caches registers and memory it has already read
+                    VmReply::Restore => outputln!(
+                        out,
+                        "restored checkpoint {}, run 'maint flush register-cache'",
+                        id
+                    ),
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            ["cond", addr, cond @ ..] => {
+                let addr = match parse_u64(addr) {
+                    Some(addr) => addr,
+                    None => {
+                        outputln!(out, "invalid breakpoint address '{}'", addr);
+                        return Ok(());
+                    }
+                };
+                let condition = if cond.is_empty() {
+                    None
+                } else {
+                    match BreakpointCondition::parse(cond) {
+                        Some(condition) => Some(condition),
+                        None => {
+                            outputln!(out, "invalid condition, expected e.g. 'r1 == 0x10'");
+                            return Ok(());
+                        }
+                    }
+                };
+                match self
+                    .request(VmRequest::SetBrkptCond(addr, condition))
+                    .map_err(|e| e.as_str())?
+                {
+                    VmReply::SetBrkptCond => {}
+                    VmReply::Err(e) => outputln!(out, "{}", e),
+                    _ => return Err("unexpected reply from VM"),
+                }
+            }
+            [] | ["help"] => {
+                outputln!(out, "monitor commands:");
+                outputln!(
+                    out,
+                    "  regs                      dump the BPF register file"
+                );
+                outputln!(out, "  tbreak <addr>             set a one-shot breakpoint");
+                outputln!(out, "  ignore <addr> <count>     skip the next count hits");
+                outputln!(
+                    out,
+                    "  breakpoints|info break    list breakpoints by number"
+                );
+                outputln!(
+                    out,
+                    "  enable|disable <addr>     turn a breakpoint on or off"
+                );
+                outputln!(
+                    out,
+                    "  delete <addr>             forget a breakpoint and its counts"
+                );
+                outputln!(
+                    out,
+                    "  cond <addr> [rN op imm]   set or clear a breakpoint condition"
+                );
+                outputln!(out, "  bt                        show the BPF call frames");
+                outputln!(
+                    out,
+                    "  disas [addr] [count]      disassemble, by default at the pc"
+                );
+                outputln!(
+                    out,
+                    "  checkpoint                snapshot registers and writable memory"
+                );
+                outputln!(out, "  restore <id>              roll back to a checkpoint");
+                outputln!(
+                    out,
+                    "  catch [call [helper..]]   stop at helper calls, all if none given"
+                );
+                outputln!(out, "  catch off                 clear helper catchpoints");
+                outputln!(
+                    out,
+                    "  set maxinsns <n>|off      stop after every n instructions"
+                );
+                outputln!(
+                    out,
+                    "  show maxinsns             show the instructions left"
+                );
+                outputln!(out, "  help                      show this message");
+            }
+            _ => outputln!(out, "unknown monitor command '{}', try 'monitor help'", cmd),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize_regs() {
+        let regs = BPFRegs {
+            regs: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            pc: 0x0807060504030201,
+            unavailable: 0,
+        };
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|b| bytes.push(b.unwrap()));
+        assert_eq!(bytes.len(), REG_WITH_PC_NUM_BYTES);
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(&hex[0..16], "0000000000000000");
+        assert_eq!(&hex[16..32], "0100000000000000");
+        assert_eq!(&hex[160..176], "0a00000000000000");
+        assert_eq!(&hex[176..], "0102030405060708");
+    }
+
+    #[test]
+    fn test_serialize_unavailable_regs() {
+        let mut regs = BPFRegs::from([1; NUM_REGS_WITH_PC]);
+        regs.unavailable = 1 << NUM_REGS;
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|b| bytes.push(b));
+        assert!(bytes[..REG_NUM_BYTES].iter().all(|b| b.is_some()));
+        assert!(bytes[REG_NUM_BYTES..].iter().all(|b| b.is_none()));
+
+        let mut bytes = Vec::new();
+        BPFRegs::unavailable().gdb_serialize(|b| bytes.push(b));
+        assert_eq!(bytes, vec![None; REG_WITH_PC_NUM_BYTES]);
+    }
+
+    #[test]
+    fn test_deserialize_regs() {
+        let mut bytes = Vec::new();
+        for i in 0..NUM_REGS_WITH_PC as u64 {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        let mut regs = BPFRegs::default();
+        assert!(regs.gdb_deserialize(&bytes[..REG_NUM_BYTES]).is_err());
+        assert!(regs.gdb_deserialize(&[&bytes[..], &[0]].concat()).is_err());
+        assert_eq!(regs, BPFRegs::default());
+        assert!(regs.gdb_deserialize(&bytes).is_ok());
+        assert_eq!(regs.regs, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(regs.pc, 11);
+    }
+
+    #[test]
+    fn test_reg_id() {
+        for id in 0..NUM_REGS_WITH_PC {
+            let (reg_id, size) = BpfReg::from_raw_id(id).unwrap();
+            assert_eq!(u8::from(reg_id) as usize, id);
+            assert_eq!(size, REG_SIZE);
+        }
+        assert_eq!(BpfReg::from_raw_id(NUM_REGS).unwrap().0, BpfReg::Pc);
+        assert_eq!(
+            BpfReg::from_raw_id(NUM_REGS_WITH_PC),
+            Some((BpfReg::W0, REG_SIZE / 2))
+        );
+        assert_eq!(BpfReg::W10.full_reg(), BpfReg::R10);
+        assert_eq!(BpfReg::R3.full_reg(), BpfReg::R3);
+        assert!(BpfReg::from_raw_id(NUM_REGS_WITH_PC + NUM_REGS).is_none());
+        assert!(BpfReg::from_raw_id(0xff).is_none());
+        assert_eq!(BpfReg::R10.dwarf_number(), Some(10));
+        assert_eq!(BpfReg::Pc.dwarf_number(), None);
+        assert_eq!(BpfReg::W0.dwarf_number(), None);
+    }
+
+    #[test]
+    fn test_target_xml_matches_regs() {
+        let names: Vec<&str> = TARGET_XML
+            .split("<reg name=\"")
+            .skip(1)
+            .map(|reg| &reg[..reg.find('"').unwrap()])
+            .collect();
+        let expected: Vec<&str> = BpfReg::ALL
+            .iter()
+            .chain(BpfReg::SUB_REGS.iter())
+            .map(|reg| reg.name())
+            .collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_remove_breakpoint() {
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_breakpoint(1);
+        breakpoints.set_breakpoint(2);
+        breakpoints.set_breakpoint(3);
+        assert_eq!(breakpoints.remove_breakpoint(2), Some(2));
+        assert_eq!(breakpoints.remove_breakpoint(2), None);
+        assert_eq!(breakpoints.remove_breakpoint(3), Some(3));
+        assert!(breakpoints.check_breakpoint(1));
+        assert!(!breakpoints.check_breakpoint(2));
+        assert!(!breakpoints.check_breakpoint(3));
+    }
+
+    #[test]
+    fn test_breakpoint_table_promotion() {
+        let thresh = BRPKT_MAP_THRESH as u64;
+        let mut breakpoints = BreakpointTable::new();
+        for addr in 0..thresh * 2 {
+            breakpoints.set_breakpoint(addr);
+        }
+        assert!(matches!(breakpoints.entries, BreakpointEntries::Many(_)));
+        // stays a set until the count drops below half of the threshold
+        let demote_at = thresh * 2 - thresh / 2 + 1;
+        for addr in 0..demote_at - 1 {
+            assert!(breakpoints.remove_breakpoint(addr).is_some());
+        }
+        assert!(matches!(breakpoints.entries, BreakpointEntries::Many(_)));
+        assert!(breakpoints.remove_breakpoint(demote_at - 1).is_some());
+        assert!(matches!(breakpoints.entries, BreakpointEntries::Few(_)));
+        for addr in 0..demote_at {
+            assert!(!breakpoints.check_breakpoint(addr));
+        }
+        for addr in demote_at..thresh * 2 {
+            assert!(breakpoints.check_breakpoint(addr));
+        }
+    }
+
+    #[test]
+    fn test_breakpoint_table_threshold() {
+        let mut breakpoints = BreakpointTable::with_threshold(4);
+        for addr in 0..6 {
+            breakpoints.set_breakpoint(addr);
+        }
+        assert!(matches!(breakpoints.entries, BreakpointEntries::Many(_)));
+        for addr in 0..5 {
+            assert!(breakpoints.remove_breakpoint(addr).is_some());
+        }
+        assert!(matches!(breakpoints.entries, BreakpointEntries::Few(_)));
+        assert!(breakpoints.check_breakpoint(5));
+    }
+
+    #[test]
+    fn test_breakpoints_stay_sorted() {
+        let mut breakpoints = BreakpointTable::new();
+        for addr in [5, 1, 9, 3, 7].iter() {
+            breakpoints.set_breakpoint(*addr);
+        }
+        assert!(breakpoints.remove_breakpoint(3).is_some());
+        let addrs: Vec<u64> = breakpoints
+            .breakpoints()
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect();
+        assert_eq!(addrs, vec![1, 5, 7, 9]);
+        if let BreakpointEntries::Few(brkpts) = &breakpoints.entries {
+            assert!(brkpts.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        }
+        for addr in addrs {
+            assert!(breakpoints.check_breakpoint(addr));
+        }
+        assert!(!breakpoints.check_breakpoint(3));
+    }
+
+    #[test]
+    fn test_lift_breakpoint() {
+        let regs = [0u64; NUM_REGS];
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_breakpoint(4);
+        let condition = BreakpointCondition::parse(&["r0", "==", "0"]);
+        assert!(breakpoints.set_condition(4, condition));
+        assert!(breakpoints.lift_breakpoint(4));
+        assert!(!breakpoints.lift_breakpoint(4));
+        assert!(!breakpoints.lift_breakpoint(5));
+        // a lifted breakpoint never stops
+        assert!(!breakpoints.hit_breakpoint(4, &regs));
+        // inserting it again brings back its number and condition
+        breakpoints.set_breakpoint(4);
+        let brkpts = breakpoints.breakpoints();
+        assert_eq!(brkpts[0].1.number, 1);
+        assert_eq!(brkpts[0].1.condition, condition);
+        assert!(breakpoints.hit_breakpoint(4, &regs));
+        // only a delete forgets it
+        assert!(breakpoints.remove_breakpoint(4).is_some());
+        assert!(!breakpoints.check_breakpoint(4));
+    }
+
+    #[test]
+    fn test_breakpoint_numbers() {
+        let mut breakpoints = BreakpointTable::new();
+        for addr in [5, 1, 9].iter() {
+            breakpoints.set_breakpoint(*addr);
+        }
+        breakpoints.remove_breakpoint(1);
+        breakpoints.set_temporary_breakpoint(1);
+        // setting an existing breakpoint again keeps its number
+        breakpoints.set_breakpoint(5);
+        let numbers: Vec<(u64, u32)> = breakpoints
+            .breakpoints()
+            .into_iter()
+            .map(|(addr, brkpt)| (addr, brkpt.number))
+            .collect();
+        assert_eq!(numbers, vec![(1, 4), (5, 1), (9, 3)]);
+    }
+
+    #[test]
+    fn test_breakpoint_condition() {
+        let mut regs = [0u64; NUM_REGS];
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_breakpoint(4);
+        assert!(breakpoints.hit_breakpoint(4, &regs));
+        let cond = BreakpointCondition::parse(&["r1", "==", "0x10"]).unwrap();
+        assert!(breakpoints.set_condition(4, Some(cond)));
+        assert!(!breakpoints.set_condition(5, Some(cond)));
+        assert!(!breakpoints.hit_breakpoint(4, &regs));
+        regs[1] = 0x10;
+        assert!(breakpoints.hit_breakpoint(4, &regs));
+        assert!(!breakpoints.hit_breakpoint(5, &regs));
+
+        assert!(BreakpointCondition::parse(&["r11", "==", "1"]).is_none());
+        assert!(BreakpointCondition::parse(&["r1", "=", "1"]).is_none());
+        assert!(BreakpointCondition::parse(&["r1", "<", "x"]).is_none());
+        assert!(BreakpointCondition::parse(&["r1", "<"]).is_none());
+    }
+
+    #[test]
+    fn test_temporary_breakpoint() {
+        let regs = [0u64; NUM_REGS];
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_temporary_breakpoint(1);
+        assert!(breakpoints.hit_breakpoint(1, &regs));
+        assert!(!breakpoints.hit_breakpoint(1, &regs));
+
+        // a permanent breakpoint at the same address survives either order
+        breakpoints.set_breakpoint(2);
+        breakpoints.set_temporary_breakpoint(2);
+        assert!(breakpoints.hit_breakpoint(2, &regs));
+        assert!(breakpoints.hit_breakpoint(2, &regs));
+        breakpoints.set_temporary_breakpoint(3);
+        breakpoints.set_breakpoint(3);
+        assert!(breakpoints.hit_breakpoint(3, &regs));
+        assert!(breakpoints.hit_breakpoint(3, &regs));
+    }
+
+    #[test]
+    fn test_breakpoint_ignore_count() {
+        let regs = [0u64; NUM_REGS];
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_breakpoint(8);
+        assert!(breakpoints.set_ignore_count(8, 2));
+        assert!(!breakpoints.set_ignore_count(9, 2));
+        assert!(!breakpoints.hit_breakpoint(8, &regs));
+        assert!(!breakpoints.hit_breakpoint(8, &regs));
+        assert!(breakpoints.hit_breakpoint(8, &regs));
+        let brkpts = breakpoints.breakpoints();
+        assert_eq!(brkpts.len(), 1);
+        assert_eq!(brkpts[0].1.hit_count, 3);
+        assert_eq!(brkpts[0].1.ignore_count, 0);
+    }
+
+    #[test]
+    fn test_disabled_breakpoint() {
+        let regs = [0u64; NUM_REGS];
+        let mut breakpoints = BreakpointTable::new();
+        breakpoints.set_breakpoint(8);
+        assert!(breakpoints.set_enabled(8, false));
+        assert!(!breakpoints.set_enabled(9, false));
+        for _ in 0..3 {
+            assert!(!breakpoints.hit_breakpoint(8, &regs));
+        }
+        // still listed, without any hits counted
+        let brkpts = breakpoints.breakpoints();
+        assert_eq!(brkpts.len(), 1);
+        assert!(!brkpts[0].1.enabled);
+        assert_eq!(brkpts[0].1.hit_count, 0);
+
+        // GDB removing and inserting it again, e.g. to step off it, keeps it disabled
+        assert!(breakpoints.lift_breakpoint(8));
+        breakpoints.set_breakpoint(8);
+        assert!(!breakpoints.hit_breakpoint(8, &regs));
+
+        assert!(breakpoints.set_enabled(8, true));
+        assert!(breakpoints.hit_breakpoint(8, &regs));
+    }
+
+    const MOCK_MEM_ADDR: u64 = 0x1000;
+
+    struct MockVm {
+        regs: [u64; NUM_REGS_WITH_PC],
+        mem: Vec<u8>,
+        text: Vec<u8>,
+    }
+
+    impl MockVm {
+        fn new() -> Self {
+            MockVm {
+                regs: [0; NUM_REGS_WITH_PC],
+                mem: vec![0; 16],
+                // lddw r0, 0x1122334455667788; exit
+                text: vec![
+                    0x18, 0x00, 0x00, 0x00, 0x88, 0x77, 0x66, 0x55, //
+                    0x00, 0x00, 0x00, 0x00, 0x44, 0x33, 0x22, 0x11, //
+                    0x95, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+                ],
+            }
+        }
+
+        fn mem_range(&self, addr: u64, len: u64) -> Result<std::ops::Range<usize>, &'static str> {
+            let start = addr
+                .checked_sub(MOCK_MEM_ADDR)
+                .ok_or("invalid memory access")? as usize;
+            let end = start + len as usize;
+            if end > self.mem.len() {
+                return Err("invalid memory access");
+            }
+            Ok(start..end)
+        }
+    }
+
+    impl DebuggableVm for MockVm {
+        fn read_regs(&self) -> [u64; NUM_REGS_WITH_PC] {
+            self.regs
+        }
+
+        fn write_reg(&mut self, id: u8, val: u64) -> Result<(), &'static str> {
+            if id as usize == NUM_REGS && val % 8 != 0 {
+                return Err("invalid pc");
+            }
+            *self.regs.get_mut(id as usize).ok_or("invalid register")? = val;
+            Ok(())
+        }
+
+        fn read_mem(&self, addr: u64, len: u64) -> Result<Vec<u8>, &'static str> {
+            let start = self.mem_range(addr, 1)?.start;
+            let len = len.min((self.mem.len() - start) as u64);
+            Ok(self.mem[self.mem_range(addr, len)?].to_vec())
+        }
+
+        fn write_mem(&mut self, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+            let range = self.mem_range(addr, bytes.len() as u64)?;
+            self.mem[range].copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn breakpoint_key(&self, addr: u64) -> Option<u64> {
+            if addr % 8 != 0 {
+                return None;
+            }
+            Some(addr / 8)
+        }
+
+        fn breakpoint_addr(&self, key: u64) -> u64 {
+            key * 8
+        }
+
+        fn section_offsets(&self) -> (u64, u64, Option<u64>) {
+            (0x100, 0x200, Some(0x300))
+        }
+
+        fn writable_regions(&self) -> Vec<(u64, u64)> {
+            vec![(MOCK_MEM_ADDR, self.mem.len() as u64)]
+        }
+
+        fn backtrace(&self) -> Vec<(u64, u64)> {
+            vec![(self.regs[NUM_REGS], 0)]
+        }
+
+        fn save_frames(&self) -> Option<CallFramesState> {
+            None
+        }
+
+        fn restore_frames(&mut self, _frames: &CallFramesState) {}
+
+        fn text(&self) -> (u64, &[u8]) {
+            (0, &self.text)
+        }
+    }
+
+    #[test]
+    fn test_step_over_breakpoint() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+        let regs = [0u64; NUM_REGS];
+
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetBrkpt(0x10)));
+        reply_rx.recv().unwrap();
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetIgnoreCount(0x10, 5)));
+        reply_rx.recv().unwrap();
+        assert!(!dbg.breakpoints.hit_breakpoint(2, &regs));
+
+        // GDB steps off the breakpoint: z0, stepi, Z0
+        assert!(!dbg.handle_request(&mut vm, VmRequest::RemoveBrkpt(0x10)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::RemoveBrkpt));
+        assert!(!dbg.breakpoints.hit_breakpoint(2, &regs));
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetBrkpt(0x10)));
+        reply_rx.recv().unwrap();
+
+        assert!(!dbg.breakpoints.hit_breakpoint(2, &regs));
+        let brkpts = dbg.breakpoints.breakpoints();
+        assert_eq!(brkpts[0].1.number, 1);
+        assert_eq!(brkpts[0].1.hit_count, 2);
+        assert_eq!(brkpts[0].1.ignore_count, 3);
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+
+        assert!(!dbg.handle_request(&mut vm, VmRequest::WriteReg(3, 42)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::WriteReg));
+        assert!(!dbg.handle_request(&mut vm, VmRequest::ReadReg(3)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::ReadReg(42)));
+
+        // a rejected pc leaves every register untouched
+        let mut regs = [1; NUM_REGS_WITH_PC];
+        regs[NUM_REGS] = 3;
+        assert!(!dbg.handle_request(&mut vm, VmRequest::WriteRegs(regs)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::Err(_)));
+        assert_eq!(vm.regs[3], 42);
+        assert_eq!(vm.regs[0], 0);
+
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetBrkpt(0x40)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::SetBrkpt));
+        assert!(dbg.breakpoints.check_breakpoint(8));
+
+        assert!(dbg.handle_request(&mut vm, VmRequest::Step));
+        assert!(dbg.step);
+    }
+
+    #[test]
+    fn test_disassemble_text() {
+        let vm = MockVm::new();
+        let insns = disassemble_text(vm.text(), 0, 10).unwrap();
+        assert_eq!(
+            insns,
+            vec![
+                (0, "lddw r0, 0x1122334455667788".to_string()),
+                (16, "exit".to_string())
+            ]
+        );
+        assert_eq!(disassemble_text(vm.text(), 0, 1).unwrap().len(), 1);
+        assert_eq!(
+            disassemble_text(vm.text(), 16, 10).unwrap(),
+            vec![(16, "exit".to_string())]
+        );
+        assert_eq!(disassemble_text(vm.text(), 4, 1), None);
+        assert_eq!(disassemble_text(vm.text(), 24, 1), None);
+    }
+
+    #[test]
+    fn test_reply_timeout() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, Duration::from_millis(10));
+        let vm = thread::spawn(move || {
+            req_rx.recv().unwrap();
+            // answer the first request too late
+            thread::sleep(Duration::from_millis(50));
+            reply_tx.send(VmReply::WriteReg).unwrap();
+            req_rx.recv().unwrap();
+            reply_tx.send(VmReply::ReadReg(7)).unwrap();
+        });
+        assert!(matches!(
+            server.request(VmRequest::WriteReg(0, 1)),
+            Err(DebugError::Timeout(_))
+        ));
+        assert!(server.reply_pending);
+
+        // the late reply is dropped rather than taken as the answer to the next request
+        server.reply_timeout = Duration::from_secs(5);
+        assert!(matches!(
+            server.request(VmRequest::ReadReg(0)),
+            Ok(VmReply::ReadReg(7))
+        ));
+        assert!(!server.reply_pending);
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn test_channel_closed() {
+        // the VM detaches instead of panicking once the server is gone
+        let (reply_tx, reply_rx) = mpsc::sync_channel(0);
+        let (_req_tx, req_rx) = mpsc::sync_channel(0);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        drop(reply_rx);
+        assert!(dbg.handle_request(&mut MockVm::new(), VmRequest::ReadRegs));
+        assert!(dbg.detached);
+
+        // and the server reports the VM going away as an error
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        drop((reply_tx, req_rx));
+        assert!(matches!(
+            server.request(VmRequest::ReadRegs),
+            Err(DebugError::ChannelClosed)
+        ));
+    }
+
+    #[test]
+    fn test_catchpoint_table() {
+        let mut catchpoints = CatchpointTable::new();
+        assert!(catchpoints.is_empty());
+        assert!(!catchpoints.catches(1));
+        catchpoints.set_catchpoints(vec![3, 1, 3]);
+        assert_eq!(catchpoints.catchpoints(), Some(&[1, 3][..]));
+        assert!(catchpoints.catches(1));
+        assert!(!catchpoints.catches(2));
+        // no ids catches every helper
+        catchpoints.set_catchpoints(Vec::new());
+        assert!(catchpoints.catches(2));
+        catchpoints.clear();
+        assert!(!catchpoints.catches(2));
+
+        assert_eq!(parse_helper_id("0x10"), 0x10);
+        assert_eq!(
+            parse_helper_id("sol_log_"),
+            ebpf::hash_symbol_name(b"sol_log_")
+        );
+    }
+
+    #[test]
+    fn test_frame_packet() {
+        assert_eq!(frame_packet(b"?"), b"$?#3f");
+        assert_eq!(frame_packet(b"g"), b"$g#67");
+        assert_eq!(frame_packet(b"OK"), b"$OK#9a");
+        assert_eq!(frame_packet(b""), b"$#00");
+
+        let (mut a, mut b) = PipeConnection::pair();
+        a.send_packet(b"qSupported").unwrap();
+        assert_eq!(b.recv_packet().unwrap(), b"qSupported");
+        for byte in b"+$OK#00" {
+            b.write(*byte).unwrap();
+        }
+        assert!(a.recv_packet().is_err());
+    }
+
+    #[test]
+    fn test_pipe_session() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let _dbg = DebugServerBuilder::new(DebugTransport::Connection(Box::new(server)))
+            .start(&[0; NUM_REGS], 0)
+            .unwrap();
+        gdb.send_packet(b"?").unwrap();
+        let mut reply = Vec::new();
+        while reply.len() < 8 {
+            reply.push(gdb.read().unwrap());
+        }
+        assert_eq!(reply, b"+$S05#b8");
+        gdb.send_packet(b"qSupported:multiprocess+").unwrap();
+        assert!(gdb.recv_packet().unwrap().starts_with(b"PacketSize="));
+    }
+
+    #[test]
+    fn test_sw_breakpoint_result() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let vm = thread::spawn(move || {
+            let mut dbg = DebugInterface::new((reply_tx, req_rx));
+            let mut vm = MockVm::new();
+            while !dbg.detached {
+                dbg.check_for_request(&mut vm, true);
+            }
+            dbg.breakpoints
+        });
+
+        assert!(matches!(server.add_sw_breakpoint(0x10), Ok(true)));
+        // unaligned addresses are not instructions
+        assert!(matches!(server.add_sw_breakpoint(0x11), Ok(false)));
+        assert!(matches!(server.remove_sw_breakpoint(0x18), Ok(false)));
+        assert!(matches!(server.remove_sw_breakpoint(0x10), Ok(true)));
+        assert!(matches!(server.remove_sw_breakpoint(0x10), Ok(false)));
+        server.send_request(VmRequest::Detach).unwrap();
+        // z0 only lifts a breakpoint, it stays in the table
+        assert!(vm
+            .join()
+            .unwrap()
+            .breakpoints()
+            .iter()
+            .all(|(_, brkpt)| !brkpt.inserted));
+    }
+
+    #[test]
+    fn test_max_write_size() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let dbg = DebugServerBuilder::new(DebugTransport::Connection(Box::new(server)))
+            .max_write_size(4)
+            .start(&[0; NUM_REGS], 0)
+            .unwrap();
+        gdb.send_packet(b"M0,8:0102030405060708").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"E02");
+        // rejected before the VM is asked
+        assert!(dbg.req.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_request_display() {
+        assert_eq!(
+            VmRequest::ReadMem(0x1000, 16).to_string(),
+            "ReadMem{addr=0x1000,len=16}"
+        );
+        assert_eq!(
+            VmRequest::WriteMem(0x20, 2, vec![0xab, 0xcd]).to_string(),
+            "WriteMem{addr=0x20,len=2,bytes=abcd}"
+        );
+        assert_eq!(
+            VmRequest::SetTempBrkpt(0x18).to_string(),
+            "SetTempBrkpt{addr=0x18}"
+        );
+        assert_eq!(VmRequest::Step.to_string(), "Step");
+        // long buffers are cut short
+        assert_eq!(
+            VmReply::ReadMem(vec![0; 100]).to_string(),
+            format!("ReadMem{{len=100,bytes={}..(100 bytes)}}", "00".repeat(16))
+        );
+        assert_eq!(VmReply::ReadReg(42).to_string(), "ReadReg{val=0x2a}");
+    }
+
+    #[test]
+    fn test_step_and_breakpoint_stops() {
+        assert!(matches!(
+            stop_reason(VmReply::DoneStep),
+            Ok(StopReason::DoneStep)
+        ));
+        assert!(matches!(
+            stop_reason(VmReply::Breakpoint),
+            Ok(StopReason::SwBreak)
+        ));
+        assert!(matches!(
+            stop_reason(VmReply::Watchpoint {
+                addr: 0x10,
+                kind: WatchKind::Write
+            }),
+            Ok(StopReason::Watch { addr: 0x10, .. })
+        ));
+    }
+
+    #[test]
+    fn test_pc_register() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let dbg = DebugServerBuilder::new(DebugTransport::Connection(Box::new(server)))
+            .start(&[0; NUM_REGS], 0)
+            .unwrap();
+        let vm = thread::spawn(move || {
+            let mut dbg = dbg;
+            let mut vm = MockVm::new();
+            while let Ok(request) = dbg.req.recv() {
+                dbg.handle_request(&mut vm, request);
+            }
+            vm
+        });
+
+        // set $pc = 0x20
+        gdb.send_packet(b"Pb=2000000000000000").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        gdb.send_packet(b"pb").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"2000000000000000");
+        // the pc follows r0..r10 in the g packet
+        gdb.send_packet(b"g").unwrap();
+        let regs = gdb.recv_packet().unwrap();
+        assert_eq!(regs.len(), REG_WITH_PC_NUM_BYTES * 2);
+        assert_eq!(&regs[NUM_REGS * REG_SIZE * 2..], b"2000000000000000");
+        // a pc in the middle of an instruction is refused and the session goes on
+        gdb.send_packet(b"Pb=2100000000000000").unwrap();
+        assert!(gdb.recv_packet().unwrap().starts_with(b"E"));
+        gdb.send_packet(b"pb").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"2000000000000000");
+
+        drop(gdb);
+        assert_eq!(vm.join().unwrap().regs[NUM_REGS], 0x20);
+    }
+
+    #[test]
+    fn test_on_disconnect_hook() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let (reason_tx, reason_rx) = mpsc::channel();
+        let _dbg = DebugServerBuilder::new(DebugTransport::Connection(Box::new(server)))
+            .on_disconnect(move |reason| reason_tx.send(reason).unwrap())
+            .start(&[0; NUM_REGS], 0)
+            .unwrap();
+        gdb.send_packet(b"?").unwrap();
+        gdb.recv_packet().unwrap();
+        drop(gdb);
+        assert!(matches!(
+            reason_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            DisconnectReason::Disconnect
+        ));
+    }
+
+    #[test]
+    fn test_detach_resumes_vm() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let (target, _reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        spawn_debug_server(
+            server,
+            || panic!("a detach doesn't wait for another GDB"),
+            SessionConfig {
+                max_packet_size: None,
+                max_sessions: None,
+                disconnect_policy: DisconnectPolicy::Stop,
+                on_disconnect: Box::new(report_disconnect),
+            },
+            target,
+        );
+        gdb.send_packet(b"D").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        assert!(matches!(
+            req_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            VmRequest::Detach
+        ));
+    }
+
+    #[test]
+    fn test_disconnect_during_continue() {
+        let (server, mut gdb) = PipeConnection::pair();
+        let (next_tx, next_rx) = mpsc::channel();
+        let (target, reply_tx, req_rx) = DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        spawn_debug_server(
+            server,
+            move || {
+                next_rx
+                    .recv()
+                    .map_err(|_| std::io::ErrorKind::NotConnected.into())
+            },
+            SessionConfig {
+                max_packet_size: None,
+                max_sessions: None,
+                disconnect_policy: DisconnectPolicy::Stop,
+                on_disconnect: Box::new(report_disconnect),
+            },
+            target,
+        );
+        let dbg = DebugInterface::new((reply_tx, req_rx));
+
+        gdb.send_packet(b"c").unwrap();
+        assert!(matches!(dbg.req.recv().unwrap(), VmRequest::Resume));
+        drop(gdb);
+
+        // the server stops the running VM once the connection is gone
+        assert!(matches!(
+            dbg.req.recv_timeout(Duration::from_secs(5)).unwrap(),
+            VmRequest::Interrupt
+        ));
+        dbg.reply.send(VmReply::Interrupt).unwrap();
+
+        // and serves the next GDB
+        let (server, mut gdb) = PipeConnection::pair();
+        next_tx.send(server).unwrap();
+        gdb.send_packet(b"?").unwrap();
+        let mut reply = Vec::new();
+        while reply.len() < 8 {
+            reply.push(gdb.read().unwrap());
+        }
+        assert_eq!(reply, b"+$S05#b8");
+    }
+
+    /// Replays a session against a server on a mock VM. Every line of the transcript is
+    /// hex, `>` the bytes GDB sent and `<` the bytes the server has to answer with
+    fn replay_transcript(transcript: &str) {
+        let (server, mut gdb) = PipeConnection::pair();
+        let (target, reply_tx, req_rx) = DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        spawn_debug_server(
+            server,
+            || Err(std::io::ErrorKind::NotConnected.into()),
+            SessionConfig {
+                max_packet_size: None,
+                max_sessions: Some(1),
+                disconnect_policy: DisconnectPolicy::Stop,
+                on_disconnect: Box::new(report_disconnect),
+            },
+            target,
+        );
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let vm = thread::spawn(move || {
+            let mut vm = MockVm::new();
+            while let Ok(request) = dbg.req.recv() {
+                dbg.handle_request(&mut vm, request);
+            }
+        });
+
+        for line in transcript.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (direction, hex) = line.split_at(1);
+            let hex = hex.trim();
+            let bytes: Vec<u8> = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                .collect();
+            match direction {
+                ">" => bytes.iter().for_each(|byte| gdb.write(*byte).unwrap()),
+                "<" => {
+                    let reply: Vec<u8> = bytes.iter().map(|_| gdb.read().unwrap()).collect();
+                    assert_eq!(
+                        String::from_utf8_lossy(&reply),
+                        String::from_utf8_lossy(&bytes)
+                    );
+                }
+                _ => panic!("bad transcript line '{}'", line),
+            }
+        }
+        drop(gdb);
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn test_transcript_attach() {
+        replay_transcript(include_str!("../tests/gdb_transcripts/attach.hex"));
+    }
+
+    #[test]
+    fn test_transcript_probes() {
+        replay_transcript(include_str!("../tests/gdb_transcripts/probes.hex"));
+    }
+
+    #[test]
+    fn test_transcript_unknown_packet() {
+        replay_transcript(include_str!("../tests/gdb_transcripts/unknown.hex"));
+    }
+
+    #[test]
+    fn test_transcript_no_ack_mode() {
+        replay_transcript(include_str!("../tests/gdb_transcripts/noack.hex"));
+    }
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(
+            hexdump(0x10, b"0123456789abcdef\x00\xff"),
+            "0x0000000000000010  30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             0x0000000000000020  00 ff                                            |..|"
+        );
+    }
+
+    #[test]
+    fn test_restart() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+        dbg.stop_at_entry = false;
+        dbg.step = true;
+
+        assert!(dbg.handle_request(&mut vm, VmRequest::Restart));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::Restart));
+        assert!(dbg.restart && dbg.stop_at_entry && !dbg.step);
+
+        // a program that ran to completion can't be started over
+        dbg.restart = false;
+        dbg.finished = true;
+        assert!(!dbg.handle_request(&mut vm, VmRequest::Restart));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::Err(_)));
+        assert!(!dbg.restart);
+    }
+
+    #[test]
+    fn test_insn_budget() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetInsnBudget(Some(100))));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::SetInsnBudget));
+        dbg.insns_left = 40;
+        assert!(!dbg.handle_request(&mut vm, VmRequest::InsnBudget));
+        assert!(matches!(
+            reply_rx.recv().unwrap(),
+            VmReply::InsnBudget(Some((100, 40)))
+        ));
+
+        // running the program again starts from the full budget
+        assert!(dbg.handle_request(&mut vm, VmRequest::Restart));
+        reply_rx.recv().unwrap();
+        assert_eq!(dbg.insns_left, 100);
+
+        assert!(!dbg.handle_request(&mut vm, VmRequest::SetInsnBudget(None)));
+        reply_rx.recv().unwrap();
+        assert!(!dbg.handle_request(&mut vm, VmRequest::InsnBudget));
+        assert!(matches!(
+            reply_rx.recv().unwrap(),
+            VmReply::InsnBudget(None)
+        ));
+    }
+
+    #[test]
+    fn test_extended_mode_pid() {
+        let (mut server, _reply_tx, _req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let other = Pid::new(2).unwrap();
+        assert!(matches!(
+            ExtendedMode::kill(&mut server, Some(VM_PID)),
+            Ok(ShouldTerminate::Yes)
+        ));
+        assert!(matches!(
+            ExtendedMode::kill(&mut server, None),
+            Ok(ShouldTerminate::Yes)
+        ));
+        assert!(matches!(
+            ExtendedMode::kill(&mut server, Some(other)),
+            Err(TargetError::NonFatal)
+        ));
+    }
+
+    #[test]
+    fn test_run_after_exit() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        // the VM drops its end once the program exited
+        drop((reply_tx, req_rx));
+        assert_eq!(
+            ExtendedMode::restart(&mut server),
+            Err("the program has exited and can't be restarted")
+        );
+        // a vRun over a session whose VM exited gets an error reply
+        let (server, mut gdb) = PipeConnection::pair();
+        let dbg = DebugServerBuilder::new(DebugTransport::Connection(Box::new(server)))
+            .start(&[0; NUM_REGS], 0)
+            .unwrap();
+        drop(dbg);
+        gdb.send_packet(b"!").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        gdb.send_packet(b"vRun;").unwrap();
+        assert!(gdb.recv_packet().unwrap().starts_with(b"E"));
+    }
+
+    #[test]
+    fn test_text_write_guard() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+        let exit = vec![0x95, 0, 0, 0, 0, 0, 0, 0];
+
+        // the text is shared with the executable and never written
+        assert!(!dbg.handle_request(&mut vm, VmRequest::WriteMem(0, 8, exit)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::Err(_)));
+        assert_eq!(vm.text[0], 0x18);
+    }
+
+    #[test]
+    fn test_reg_from_le_bytes() {
+        assert_eq!(
+            reg_from_le_bytes(BpfReg::R1, &0x1122334455667788u64.to_le_bytes()),
+            Some(0x1122334455667788)
+        );
+        assert_eq!(
+            reg_from_le_bytes(BpfReg::W1, &0x88776655u32.to_le_bytes()),
+            Some(0x88776655)
+        );
+        assert_eq!(reg_from_le_bytes(BpfReg::R1, &[]), None);
+        assert_eq!(reg_from_le_bytes(BpfReg::R1, &[0; 9]), None);
+    }
+
+    #[test]
+    fn test_write_sub_reg_width() {
+        let (mut server, _reply_tx, _req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        // rejected before the VM is asked
+        assert!(matches!(
+            server.write_register(BpfReg::W0, &[0; 8]),
+            Err(TargetError::NonFatal)
+        ));
+        assert!(matches!(
+            server.write_register(BpfReg::W10, &[0; 8]),
+            Err(TargetError::NonFatal)
+        ));
+    }
+
+    #[test]
+    fn test_write_full_reg_width() {
+        let (mut server, _reply_tx, _req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        assert!(matches!(
+            server.write_register(BpfReg::R0, &[0; 4]),
+            Err(TargetError::NonFatal)
+        ));
+        assert!(matches!(
+            server.write_register(BpfReg::Pc, &[0; 4]),
+            Err(TargetError::NonFatal)
+        ));
+    }
+
+    #[test]
+    fn test_short_read_policy() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let vm = thread::spawn(move || {
+            for _ in 0..3 {
+                req_rx.recv().unwrap();
+                reply_tx.send(VmReply::ReadMem(vec![1, 2])).unwrap();
+            }
+        });
+        let mut dst = [0xff; 4];
+        assert!(matches!(
+            server.read_addrs(0, &mut dst),
+            Err(TargetError::Errno(0))
+        ));
+        server.short_read_policy = ShortReadPolicy::ZeroFill;
+        assert!(server.read_addrs(0, &mut dst).is_ok());
+        assert_eq!(dst, [1, 2, 0, 0]);
+        // more bytes than were asked for is a bug in the VM
+        assert!(matches!(
+            server.read_addrs(0, &mut dst[..1]),
+            Err(TargetError::Fatal(_))
+        ));
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_past_region_end() {
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+        vm.mem[12..].copy_from_slice(&[1, 2, 3, 4]);
+
+        // the last 4 bytes of the region and 4 beyond it
+        assert!(!dbg.handle_request(&mut vm, VmRequest::ReadMem(MOCK_MEM_ADDR + 12, 8)));
+        match reply_rx.recv().unwrap() {
+            VmReply::ReadMem(bytes) => assert_eq!(bytes, vec![1, 2, 3, 4]),
+            _ => panic!("expected a short read"),
+        }
+        assert!(!dbg.handle_request(&mut vm, VmRequest::ReadMem(MOCK_MEM_ADDR + 16, 8)));
+        assert!(matches!(reply_rx.recv().unwrap(), VmReply::Err(_)));
+    }
+
+    #[test]
+    fn test_breakpoint_edits_while_running() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let vm = thread::spawn(move || {
+            let mut vm = MockVm::new();
+            let regs = [0; NUM_REGS];
+            // walk over the first few instructions until the server goes away
+            for pc in (0..4).cycle() {
+                dbg.breakpoints.hit_breakpoint(pc, &regs);
+                dbg.check_for_request(&mut vm, false);
+                if dbg.detached {
+                    break;
+                }
+            }
+            dbg.breakpoints
+        });
+
+        for i in 0..1000 {
+            let addr = (i % 4) * 8;
+            assert!(matches!(
+                server.request(VmRequest::SetBrkpt(addr)),
+                Ok(VmReply::SetBrkpt)
+            ));
+            assert!(matches!(
+                server.request(VmRequest::RemoveBrkpt(addr)),
+                Ok(VmReply::RemoveBrkpt)
+            ));
+            server.send_request(VmRequest::Resume).unwrap();
+        }
+        drop(server);
+        // z0 only lifts a breakpoint, it stays in the table
+        assert!(vm
+            .join()
+            .unwrap()
+            .breakpoints()
+            .iter()
+            .all(|(_, brkpt)| !brkpt.inserted));
+    }
+
+    #[test]
+    fn test_section_offsets() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let vm = thread::spawn(move || {
+            let request = dbg.req.recv().unwrap();
+            dbg.handle_request(&mut MockVm::new(), request);
+        });
+        assert!(matches!(
+            server.get_section_offsets(),
+            Ok(Offsets::Sections {
+                text: 0x100,
+                data: 0x200,
+                bss: Some(0x300),
+            })
+        ));
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn test_mem_cache() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        server.mem_cache = Some(MemCache::new(1));
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let vm = thread::spawn(move || {
+            let mut vm = MockVm::new();
+            let mut reads = 0;
+            while let Ok(request) = dbg.req.recv() {
+                if let VmRequest::ReadMem(..) = request {
+                    reads += 1;
+                }
+                dbg.handle_request(&mut vm, request);
+            }
+            reads
+        });
+
+        let mut buf = [0u8; 4];
+        for addr in 0..4 {
+            assert!(server.read_addrs(MOCK_MEM_ADDR + addr, &mut buf).is_ok());
+        }
+        // a write goes to the VM and drops the cache
+        assert!(server.write_addrs(MOCK_MEM_ADDR, &[1, 2]).is_ok());
+        assert!(server.read_addrs(MOCK_MEM_ADDR, &mut buf).is_ok());
+        assert_eq!(buf, [1, 2, 0, 0]);
+        // the mock's memory ends within the page, so this read goes to the VM and fails
+        let mut buf = [0u8; 32];
+        assert!(server.read_addrs(MOCK_MEM_ADDR, &mut buf).is_err());
+        drop(server);
+        assert_eq!(vm.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_interrupt_after_breakpoint() {
+        let (mut server, reply_tx, req_rx) =
+            DebugServer::new(&[0; NUM_REGS], 0, DEFAULT_REPLY_TIMEOUT);
+        let vm = thread::spawn(move || {
+            let mut dbg = DebugInterface::new((reply_tx, req_rx));
+            let mut vm = MockVm::new();
+            dbg.check_for_request(&mut vm, true);
+            // the breakpoint fires while the server is deciding to interrupt
+            dbg.send_reply(VmReply::Breakpoint);
+            dbg.check_for_request(&mut vm, true);
+        });
+        server.send_request(VmRequest::Resume).unwrap();
+        // the interrupt gets the breakpoint back and no reply of its own
+        assert!(matches!(
+            stop_reason(server.request(VmRequest::Interrupt).unwrap()),
+            Ok(StopReason::SwBreak)
+        ));
+        assert!(matches!(
+            server.request(VmRequest::ReadReg(0)),
+            Ok(VmReply::ReadReg(0))
+        ));
+        server.send_request(VmRequest::Resume).unwrap();
+        vm.join().unwrap();
+    }
+
+    #[test]
+    fn test_fault_signal() {
+        use crate::user_error::UserError;
+        assert_eq!(
+            fault_signal::<UserError>(&EbpfError::DivideByZero(30)),
+            SIGFPE
+        );
+        assert_eq!(
+            fault_signal::<UserError>(&EbpfError::InvalidVirtualAddress(0)),
+            SIGSEGV
+        );
+        assert_eq!(
+            fault_signal::<UserError>(&EbpfError::UnsupportedInstruction(30)),
+            SIGILL
+        );
+        assert_eq!(
+            fault_signal::<UserError>(&EbpfError::ExceededMaxInstructions(30, 1)),
+            SIGXCPU
+        );
+        assert_eq!(
+            fault_signal::<UserError>(&EbpfError::JITNotCompiled),
+            SIGABRT
+        );
+    }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let (reply_tx, _reply_rx) = mpsc::sync_channel(1);
+        let (_req_tx, req_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        let mut vm = MockVm::new();
+
+        vm.regs[1] = 7;
+        vm.mem[3] = 0xaa;
+        let id = dbg.checkpoint(&vm).unwrap();
+        vm.regs[1] = 8;
+        vm.regs[NUM_REGS] = 0x40;
+        vm.mem[3] = 0xbb;
+        assert!(dbg.restore(&mut vm, id).is_ok());
+        assert_eq!(vm.regs[1], 7);
+        assert_eq!(vm.regs[NUM_REGS], 0);
+        assert_eq!(vm.mem[3], 0xaa);
+        assert!(dbg.restore(&mut vm, id + 1).is_err());
+    }
+
+    #[test]
+    fn test_regs_round_trip() {
+        let regs = BPFRegs {
+            regs: [
+                0,
+                u64::MAX,
+                0x0102030405060708,
+                3,
+                4,
+                5,
+                6,
+                7,
+                8,
+                9,
+                0x200001000,
+            ],
+            pc: 0x100000120,
+            unavailable: 0,
+        };
+        let mut bytes = Vec::new();
+        regs.gdb_serialize(|b| bytes.push(b.unwrap()));
+        let mut deserialized = BPFRegs::default();
+        assert!(deserialized.gdb_deserialize(&bytes).is_ok());
+        assert_eq!(deserialized, regs);
+        assert_eq!(BPFRegs::from(<[u64; NUM_REGS_WITH_PC]>::from(&regs)), regs);
+    }
+
+    #[test]
+    fn test_watch_packet_len() {
+        assert_eq!(watch_packet_len(b"Z2,1000,4"), Some(4));
+        assert_eq!(watch_packet_len(b"z4,400000002,1"), Some(1));
+        assert_eq!(watch_packet_len(b"Z0,8,8"), None);
+        assert_eq!(watch_packet_len(b"Z2,1000"), None);
+        assert_eq!(watch_packet_len(b"m1000,4"), None);
+    }
+
+    #[test]
+    fn test_watchpoint_table() {
+        let mut watchpoints = WatchpointTable::new();
+        watchpoints.set_watchpoint(0x1000, 8, WatchKind::Write);
+        watchpoints.set_watchpoint(0x2000, 2, WatchKind::Read);
+        // a store to any byte of the watched range fires
+        assert_eq!(
+            watchpoints.check_access(0x1007, 1, true),
+            Some((0x1000, WatchKind::Write))
+        );
+        assert_eq!(
+            watchpoints.check_access(0xffc, 8, true),
+            Some((0x1000, WatchKind::Write))
+        );
+        assert_eq!(watchpoints.check_access(0x1008, 1, true), None);
+        assert_eq!(watchpoints.check_access(0x1000, 8, false), None);
+        // only the watched length counts, not the widest access
+        assert_eq!(
+            watchpoints.check_access(0x2001, 4, false),
+            Some((0x2000, WatchKind::Read))
+        );
+        assert_eq!(watchpoints.check_access(0x2002, 4, false), None);
+        assert!(!watchpoints.remove_watchpoint(0x1000, 4, WatchKind::Write));
+        assert!(watchpoints.remove_watchpoint(0x1000, 8, WatchKind::Write));
+        assert!(!watchpoints.remove_watchpoint(0x1000, 8, WatchKind::Write));
+        assert_eq!(watchpoints.check_access(0x1000, 8, true), None);
     }
 }
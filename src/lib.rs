@@ -41,3 +41,9 @@ pub mod vm;
 
 #[cfg(feature = "debug")]
 mod gdb_stub;
+
+#[cfg(feature = "debug")]
+pub use gdb_stub::{
+    BreakpointTable, DebugError, DebugServerBuilder, DebugTransport, DisconnectPolicy,
+    PipeConnection, ShortReadPolicy,
+};
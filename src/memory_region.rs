@@ -150,6 +150,11 @@ impl<'a> MemoryMapping<'a> {
         Err(self.generate_access_violation(access_type, vm_addr, len))
     }
 
+    /// Returns the mapped regions, not in address order
+    pub fn get_regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
     /// Resize the memory_region at the given index
     pub fn resize_region<E: UserDefinedError>(
         &mut self,
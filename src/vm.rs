@@ -24,13 +24,16 @@ use log::debug;
 use std::{collections::HashMap, fmt::Debug, u32};
 
 #[cfg(feature = "debug")]
-use crate::gdb_stub::{start_debug_server, BreakpointTable, VmReply, VmRequest};
+use crate::call_frames::CallFramesState;
 #[cfg(feature = "debug")]
-use gdbstub::target::ext::base::singlethread::{ResumeAction, SingleThreadOps, StopReason};
+use crate::gdb_stub::{
+    fault_signal, CatchpointTable, DebugInterface, DebugServerBuilder, DebuggableVm, VmReply,
+    VmRequest, WatchpointTable,
+};
 #[cfg(feature = "debug")]
-use gdbstub::target::ext::section_offsets::Offsets;
+use gdbstub::target::ext::breakpoints::WatchKind;
 #[cfg(feature = "debug")]
-use std::sync::mpsc;
+use std::{sync::mpsc, thread};
 
 /// eBPF verification function that returns an error if the program does not meet its requirements.
 ///
@@ -206,6 +209,8 @@ pub trait Executable<E: UserDefinedError, I: InstructionMeter>: Send + Sync {
     fn get_text_bytes(&self) -> Result<(u64, &[u8]), EbpfError<E>>;
     /// Get a vector of virtual addresses for each read-only section
     fn get_ro_sections(&self) -> Result<Vec<(u64, &[u8])>, EbpfError<E>>;
+    /// Get how far the .text section and the read-only sections were moved from their ELF addresses
+    fn get_section_offsets(&self) -> (u64, Option<u64>);
     /// Get the entry point offset into the text section
     fn get_entrypoint_instruction_offset(&self) -> Result<usize, EbpfError<E>>;
     /// Set a symbol's instruction offset
@@ -389,6 +394,12 @@ pub struct EbpfVm<'a, E: UserDefinedError, I: InstructionMeter> {
     frames: CallFrames,
     last_insn_count: u64,
     total_insn_count: u64,
+    /// used by the next interpreted run instead of waiting for GDB
+    #[cfg(feature = "debug")]
+    debugger: Option<DebugInterface>,
+    /// started by the next interpreted run, which then waits for GDB
+    #[cfg(feature = "debug")]
+    debug_server: Option<DebugServerBuilder>,
 }
 
 impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
@@ -457,6 +468,10 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
             frames,
             last_insn_count: 0,
             total_insn_count: 0,
+            #[cfg(feature = "debug")]
+            debugger: None,
+            #[cfg(feature = "debug")]
+            debug_server: None,
         };
         unsafe {
             libc::memcpy(
@@ -593,76 +608,192 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
         result
     }
 
-    // TODO make this not use unwrap
+    /// Debugs the next interpreted run with GDB. The run starts the server configured by
+    /// `debug_server` and stops at the entrypoint until GDB has connected and resumed it.
+    /// Runs after that one, and JIT runs, are not debugged.
     #[cfg(feature = "debug")]
-    fn handle_dbg_request(
+    pub fn set_debugger(&mut self, debug_server: DebugServerBuilder) {
+        self.debug_server = Some(debug_server);
+    }
+
+    /// Runs the program in the interpreter until it reaches one of `breakpoints`, given as
+    /// instruction indices, and reports the registers there. Returns `None` if the program
+    /// finished without reaching a breakpoint.
+    ///
+    /// This is the entry point for scripts and automated analysis, no GDB and no socket
+    /// are involved.
+    #[cfg(feature = "debug")]
+    pub fn debug_once(
         &mut self,
-        request: VmRequest,
-        reply: &mut mpsc::SyncSender<VmReply>,
-        req: &mut mpsc::Receiver<VmRequest>,
-        breakpoints: &mut BreakpointTable,
-        step: &mut bool,
-    ) {
-        match request {
-            VmRequest::Resume => {}
-            VmRequest::Interrupt => {
-                reply.send(VmReply::Interrupt).unwrap();
-                self.check_for_dbg_request(true, reply, req, breakpoints, step);
-            }
-            VmRequest::Step => {
-                *step = true;
-            }
-            VmRequest::SetBrkpt(addr) => {
-                breakpoints.set_breakpoint(addr);
-                reply.send(VmReply::SetBrkpt).unwrap();
+        instruction_meter: &mut I,
+        breakpoints: &[usize],
+    ) -> Result<Option<StopReport>, EbpfError<E>> {
+        let (req_tx, req_rx) = mpsc::sync_channel(0);
+        let (reply_tx, reply_rx) = mpsc::sync_channel(1);
+        let mut dbg = DebugInterface::new((reply_tx, req_rx));
+        dbg.stop_at_entry = false;
+        for pc in breakpoints {
+            dbg.breakpoints.set_breakpoint(*pc as u64);
+        }
+        self.debugger = Some(dbg);
+
+        // Stands in for the debug server: takes the first stop, reads the registers
+        // there and ends the program
+        let server = thread::spawn(move || match reply_rx.recv() {
+            Ok(VmReply::Breakpoint) => {
+                req_tx.send(VmRequest::ReadRegs).ok()?;
+                let regs = match reply_rx.recv() {
+                    Ok(VmReply::ReadRegs(regs)) => regs,
+                    _ => return None,
+                };
+                let _ = req_tx.send(VmRequest::Kill);
+                Some(regs)
             }
-            VmRequest::RemoveBrkpt(addr) => {
-                breakpoints.remove_breakpoint(addr);
+            // a fault, which the program's result reports
+            Ok(VmReply::Signal(_)) => {
+                let _ = req_tx.send(VmRequest::Detach);
+                None
             }
-            VmRequest::Offsets => {
-                let res = match self.executable.get_text_bytes() {
-                    Ok(text) => {
-                        let (text, _) = text;
-                        VmReply::Offsets(Offsets::Segments {
-                            text_seg: text,
-                            data_seg: None,
-                        })
-                    }
-                    Err(_) => VmReply::Err("could not fetch offsets")
+            _ => None,
+        });
+        let result = self.execute_program_interpreted(instruction_meter);
+        let regs = server.join().ok().flatten();
+        match (result, regs) {
+            (Err(EbpfError::KilledByDebugger(_)), Some(regs)) => {
+                let mut report = StopReport {
+                    pc: (regs[11] - self.program_vm_addr) as usize / ebpf::INSN_SIZE,
+                    regs: [0; 11],
                 };
-                reply.send(res).unwrap();
-            }
-            _ => {
-                reply.send(VmReply::Err("unimplemented")).unwrap();
+                report.regs.copy_from_slice(&regs[0..11]);
+                Ok(Some(report))
             }
+            (Err(err), _) => Err(err),
+            (Ok(_), _) => Ok(None),
         }
     }
 
-    // TODO make this not use unwrap
+    /// Converts an instruction index into the byte address the debugger uses for it
+    #[cfg(feature = "debug")]
+    fn pc_to_dbg_addr(&self, pc: usize) -> u64 {
+        self.program_vm_addr + (pc * ebpf::INSN_SIZE) as u64
+    }
+
+    /// Converts a byte address from the debugger into an instruction index,
+    /// fails if the address is not an instruction in the text section
+    #[cfg(feature = "debug")]
+    fn dbg_addr_to_pc(&self, addr: u64) -> Option<usize> {
+        let offset = addr.checked_sub(self.program_vm_addr)? as usize;
+        if !offset.is_multiple_of(ebpf::INSN_SIZE) || offset + ebpf::INSN_SIZE > self.program.len()
+        {
+            return None;
+        }
+        Some(offset / ebpf::INSN_SIZE)
+    }
+
+    /// Returns the watchpoint hit by the memory access of `insn`, if any
+    #[cfg(feature = "debug")]
+    fn check_watchpoints(
+        watchpoints: &WatchpointTable,
+        insn: &ebpf::Insn,
+        reg: &[u64; 11],
+    ) -> Option<(u64, WatchKind)> {
+        let (vm_addr, is_store) = match insn.opc & ebpf::BPF_CLS_MASK {
+            ebpf::BPF_LDX => (
+                (reg[insn.src as usize] as i64).wrapping_add(insn.off as i64) as u64,
+                false,
+            ),
+            ebpf::BPF_ST | ebpf::BPF_STX => (
+                (reg[insn.dst as usize] as i64).wrapping_add(insn.off as i64) as u64,
+                true,
+            ),
+            // the mode field sits in the top three bits, LD_DW_IMM doesn't touch memory
+            ebpf::BPF_LD => match insn.opc & 0xe0 {
+                ebpf::BPF_ABS => (
+                    ebpf::MM_INPUT_START.wrapping_add(insn.imm as u32 as u64),
+                    false,
+                ),
+                ebpf::BPF_IND => (
+                    ebpf::MM_INPUT_START
+                        .wrapping_add(reg[insn.src as usize])
+                        .wrapping_add(insn.imm as u32 as u64),
+                    false,
+                ),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // the size field sits in bits 3 and 4 of the opcode
+        let len = match insn.opc & 0x18 {
+            ebpf::BPF_B => 1,
+            ebpf::BPF_H => 2,
+            ebpf::BPF_W => 4,
+            _ => 8,
+        };
+        watchpoints.check_access(vm_addr, len, is_store)
+    }
+
+    /// Returns the helper called by the instruction at `pc` if a catchpoint is set on it
+    #[cfg(feature = "debug")]
+    fn check_catchpoints(&self, catchpoints: &CatchpointTable, pc: usize) -> Option<u32> {
+        if catchpoints.is_empty() {
+            return None;
+        }
+        let insn = ebpf::get_insn_unchecked(self.program, pc);
+        let id = insn.imm as u32;
+        if insn.opc == ebpf::CALL_IMM
+            && catchpoints.catches(id)
+            && self
+                .executable
+                .get_syscall_registry()
+                .lookup_syscall(id)
+                .is_some()
+        {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Polls the debugger for a request, if `block` is set or the request stops the VM
+    /// keeps serving requests until the debugger resumes execution
     #[cfg(feature = "debug")]
     fn check_for_dbg_request(
         &mut self,
         block: bool,
-        reply: &mut mpsc::SyncSender<VmReply>,
-        req: &mut mpsc::Receiver<VmRequest>,
-        breakpoints: &mut BreakpointTable,
-        step: &mut bool,
+        dbg: &mut DebugInterface,
+        reg: &mut [u64; 11],
+        pc: &mut usize,
     ) {
+        dbg.check_for_request(&mut InterpreterDebugState { vm: self, reg, pc }, block);
+    }
 
-        if block {
-            if let Ok(request) = req.recv() {
-                self.handle_dbg_request(request, reply, req, breakpoints, step);
-            } else {
-                eprintln!("debugger detatched from VM");
-                std::process::exit(1);
+    /// Tells the debugger how execution ended. A fault is reported as a signal
+    /// with the VM left at the faulting instruction, once GDB resumes it the
+    /// program is gone and reported as terminated by that signal.
+    #[cfg(feature = "debug")]
+    fn report_dbg_result(
+        &mut self,
+        result: &ProgramResult<E>,
+        dbg: &mut DebugInterface,
+        reg: &mut [u64; 11],
+        mut pc: usize,
+    ) {
+        match result {
+            Ok(r0) => {
+                // GDB only takes a byte of exit status, saturate so a failing
+                // program never shows up as a clean exit
+                let code = (*r0).min(u8::MAX as u64) as u8;
+                dbg.send_reply(VmReply::Exited(code));
             }
-        } else {
-            match req.try_recv() {
-                Ok(request) => self.handle_dbg_request(request, reply, req, breakpoints, step),
-                Err(mpsc::TryRecvError::Empty) => {},
-                Err(mpsc::TryRecvError::Disconnected) => {
-                    eprintln!("debugger detatched from VM");
-                    std::process::exit(1);
+            // the debugger asked for this and is no longer listening
+            Err(EbpfError::KilledByDebugger(_)) => {}
+            Err(err) => {
+                let signal = fault_signal(err);
+                dbg.finished = true;
+                dbg.send_reply(VmReply::Signal(signal));
+                self.check_for_dbg_request(true, dbg, reg, &mut pc);
+                if !dbg.detached && !dbg.killed {
+                    dbg.send_reply(VmReply::Terminated(signal));
                 }
             }
         }
@@ -673,8 +804,6 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
         &mut self,
         instruction_meter: &mut I,
     ) -> ProgramResult<E> {
-        const U32MAX: u64 = u32::MAX as u64;
-
         // R1 points to beginning of input memory, R10 to the stack of the first frame
         let mut reg: [u64; 11] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, self.frames.get_stack_top()];
 
@@ -682,42 +811,123 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
             reg[1] = ebpf::MM_INPUT_START;
         }
 
-        // Check config outside of the instruction loop
-        let instruction_meter_enabled = self.executable.get_config().enable_instruction_meter;
-        let instruction_tracing_enabled = self.executable.get_config().enable_instruction_tracing;
-
-        // Loop on instructions
         let entry = self.executable.get_entrypoint_instruction_offset()?;
-        let mut next_pc: usize = entry;
 
         #[cfg(feature = "debug")]
-        let mut dbg_interface = (start_debug_server(10000, &reg, next_pc as u64), BreakpointTable::new());
+        let mut dbg = match (self.debugger.take(), self.debug_server.take()) {
+            (Some(dbg), _) => Some(dbg),
+            // a run that was asked to be debugged never goes ahead without the debugger
+            (None, Some(debug_server)) => Some(
+                debug_server
+                    .start(&reg, self.pc_to_dbg_addr(entry))
+                    .map_err(|e| EbpfError::DebuggerFailed(e.to_string()))?,
+            ),
+            (None, None) => None,
+        };
+        #[cfg(feature = "debug")]
+        let mut current_pc = entry;
+
+        let result = self.execute_program_interpreted_loop(
+            instruction_meter,
+            &mut reg,
+            entry,
+            #[cfg(feature = "debug")]
+            &mut dbg,
+            #[cfg(feature = "debug")]
+            &mut current_pc,
+        );
 
         #[cfg(feature = "debug")]
-        let mut step = false;
+        if let Some(dbg) = dbg.as_mut() {
+            self.report_dbg_result(&result, dbg, &mut reg, current_pc);
+        }
+
+        result
+    }
+
+    /// Runs the program from `entry` until it exits or faults, under the debugger if
+    /// `dbg` holds one. `current_pc` is left at the last instruction executed
+    #[rustfmt::skip]
+    fn execute_program_interpreted_loop(
+        &mut self,
+        instruction_meter: &mut I,
+        reg: &mut [u64; 11],
+        entry: usize,
+        #[cfg(feature = "debug")] dbg: &mut Option<DebugInterface>,
+        #[cfg(feature = "debug")] current_pc: &mut usize,
+    ) -> ProgramResult<E> {
+        const U32MAX: u64 = u32::MAX as u64;
+
+        // Check config outside of the instruction loop
+        let instruction_meter_enabled = self.executable.get_config().enable_instruction_meter;
+        let instruction_tracing_enabled = self.executable.get_config().enable_instruction_tracing;
 
+        // Loop on instructions
+        let mut next_pc: usize = entry;
         let mut remaining_insn_count = if instruction_meter_enabled { instruction_meter.get_remaining() } else { 0 };
         let initial_insn_count = remaining_insn_count;
         self.last_insn_count = 0;
-        while next_pc * ebpf::INSN_SIZE + ebpf::INSN_SIZE <= self.program.len() {
-            let pc = next_pc;
+        #[cfg(feature = "debug")]
+        let init_reg = *reg;
 
-            // TODO make this not use unwrap()
+        while next_pc * ebpf::INSN_SIZE + ebpf::INSN_SIZE <= self.program.len() {
             #[cfg(feature = "debug")]
-            {
-                let ((ref mut reply, ref mut req), ref mut breakpoints) = dbg_interface;
-                if step {
-                    step = false;
-                    self.check_for_dbg_request(true, reply, req, breakpoints, &mut step);
-                } else if breakpoints.check_breakpoint(pc as u64) {
-                    reply.send(VmReply::Breakpoint).unwrap();
-                    self.check_for_dbg_request(true, reply, req, breakpoints, &mut step);
+            if let Some(dbg) = dbg.as_mut() {
+                if dbg.stop_at_entry {
+                    dbg.stop_at_entry = false;
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if let Some((addr, kind)) = dbg.pending_watch.take() {
+                    dbg.step = false;
+                    dbg.send_reply(VmReply::Watchpoint { addr, kind });
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if let Some(id) = dbg.pending_catch.take() {
+                    dbg.step = false;
+                    dbg.send_reply(VmReply::SyscallReturn(id));
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if dbg.step {
+                    dbg.step = false;
+                    dbg.send_reply(VmReply::DoneStep);
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if dbg.breakpoints.hit_breakpoint(next_pc as u64, reg) {
+                    dbg.send_reply(VmReply::Breakpoint);
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if let Some(id) = self.check_catchpoints(&dbg.catchpoints, next_pc) {
+                    dbg.send_reply(VmReply::SyscallEntry(id));
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
+                } else if dbg.insn_budget.is_some() && dbg.insns_left == 0 {
+                    // resuming grants another full budget
+                    dbg.insns_left = dbg.insn_budget.unwrap_or(0);
+                    dbg.send_reply(VmReply::BudgetExhausted);
+                    self.check_for_dbg_request(true, dbg, reg, &mut next_pc);
                 } else {
-                    self.check_for_dbg_request(false, reply, req, breakpoints, &mut step);
+                    self.check_for_dbg_request(false, dbg, reg, &mut next_pc);
+                }
+                if dbg.killed {
+                    return Err(EbpfError::KilledByDebugger(next_pc + ebpf::ELF_INSN_DUMP_OFFSET));
+                }
+                if dbg.restart {
+                    // breakpoints and memory are left as they are
+                    dbg.restart = false;
+                    *reg = init_reg;
+                    next_pc = entry;
+                    self.frames.reset();
+                    self.last_insn_count = 0;
+                    remaining_insn_count = initial_insn_count;
+                    continue;
+                }
+                if dbg.insn_budget.is_some() {
+                    dbg.insns_left = dbg.insns_left.saturating_sub(1);
                 }
             }
+            #[cfg(feature = "debug")]
+            if dbg.as_ref().is_some_and(|dbg| dbg.detached) {
+                *dbg = None;
+            }
 
+            let pc = next_pc;
             next_pc += 1;
+            #[cfg(feature = "debug")]
+            { *current_pc = pc; }
             let insn = ebpf::get_insn_unchecked(self.program, pc);
             let dst = insn.dst as usize;
             let src = insn.src as usize;
@@ -725,11 +935,19 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
 
             if instruction_tracing_enabled {
                 let mut state = [0u64; 12];
-                state[0..11].copy_from_slice(&reg);
+                state[0..11].copy_from_slice(reg);
                 state[11] = pc as u64;
                 self.tracer.trace(state);
             }
 
+            // the hit is reported once the access is done, at the top of the next iteration
+            #[cfg(feature = "debug")]
+            if let Some(dbg) = dbg.as_mut() {
+                if !dbg.watchpoints.is_empty() {
+                    dbg.pending_watch = Self::check_watchpoints(&dbg.watchpoints, &insn, reg);
+                }
+            }
+
             match insn.opc {
 
                 // BPF_LD class
@@ -996,6 +1214,12 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
                         if instruction_meter_enabled {
                             remaining_insn_count = instruction_meter.get_remaining();
                         }
+                        #[cfg(feature = "debug")]
+                        if let Some(dbg) = dbg.as_mut() {
+                            if dbg.catchpoints.catches(insn.imm as u32) {
+                                dbg.pending_catch = Some(insn.imm as u32);
+                            }
+                        }
                     } else if let Some(target_pc) = self.executable.lookup_bpf_function(insn.imm as u32) {
                         // make BPF to BPF call
                         reg[ebpf::STACK_REG] = self.frames.push(
@@ -1036,14 +1260,6 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
             }
         }
 
-        // TODO make this not use unwrap
-        #[cfg(feature = "debug")]
-        {
-            let ((ref mut reply, _), _) = dbg_interface;
-            reply.send(VmReply::Halted).unwrap();
-        }
-
-
         Err(EbpfError::ExecutionOverrun(
             next_pc + ebpf::ELF_INSN_DUMP_OFFSET,
         ))
@@ -1119,3 +1335,144 @@ impl<'a, E: UserDefinedError, I: InstructionMeter> EbpfVm<'a, E, I> {
         }
     }
 }
+
+/// Where `EbpfVm::debug_once` stopped the program
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopReport {
+    /// Instruction index of the breakpoint
+    pub pc: usize,
+    /// r0 to r10
+    pub regs: [u64; 11],
+}
+
+/// The interpreter's state while it is stopped for the debugger
+#[cfg(feature = "debug")]
+struct InterpreterDebugState<'a, 'b, E: UserDefinedError, I: InstructionMeter> {
+    vm: &'a mut EbpfVm<'b, E, I>,
+    reg: &'a mut [u64; 11],
+    pc: &'a mut usize,
+}
+
+#[cfg(feature = "debug")]
+impl<'a, 'b, E: UserDefinedError, I: InstructionMeter> DebuggableVm
+    for InterpreterDebugState<'a, 'b, E, I>
+{
+    fn read_regs(&self) -> [u64; 12] {
+        let mut regs = [0u64; 12];
+        regs[0..11].copy_from_slice(self.reg);
+        regs[11] = self.vm.pc_to_dbg_addr(*self.pc);
+        regs
+    }
+
+    fn write_reg(&mut self, id: u8, val: u64) -> Result<(), &'static str> {
+        match id as usize {
+            id if id < self.reg.len() => self.reg[id] = val,
+            11 => *self.pc = self.vm.dbg_addr_to_pc(val).ok_or("invalid pc")?,
+            _ => return Err("invalid register"),
+        }
+        Ok(())
+    }
+
+    fn read_mem(&self, addr: u64, len: u64) -> Result<Vec<u8>, &'static str> {
+        let readable = |len| {
+            self.vm
+                .memory_mapping
+                .map::<UserError>(AccessType::Load, addr, len)
+                .is_ok()
+        };
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        if !readable(1) {
+            return Err("invalid memory access");
+        }
+        // a read straddling the end of a region stops there rather than running into
+        // whatever host memory follows it
+        let (mut lo, mut hi) = (1, len);
+        while lo < hi {
+            let mid = hi - (hi - lo) / 2;
+            if readable(mid) {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+        let host_addr = self
+            .vm
+            .memory_mapping
+            .map::<UserError>(AccessType::Load, addr, lo)
+            .map_err(|_| "invalid memory access")?;
+        Ok(unsafe { std::slice::from_raw_parts(host_addr as *const u8, lo as usize) }.to_vec())
+    }
+
+    fn write_mem(&mut self, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+        let host_addr = self
+            .vm
+            .memory_mapping
+            .map::<UserError>(AccessType::Store, addr, bytes.len() as u64)
+            .map_err(|_| "invalid memory access")?;
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), host_addr as *mut u8, bytes.len()) };
+        Ok(())
+    }
+
+    /// Breakpoints are kept as instruction indices to match the interpreter's pc
+    fn breakpoint_key(&self, addr: u64) -> Option<u64> {
+        self.vm.dbg_addr_to_pc(addr).map(|pc| pc as u64)
+    }
+
+    fn breakpoint_addr(&self, key: u64) -> u64 {
+        self.vm.pc_to_dbg_addr(key as usize)
+    }
+
+    fn section_offsets(&self) -> (u64, u64, Option<u64>) {
+        // the read-only sections are all the data that gets loaded, bss moves along with it
+        let (text, rodata) = self.vm.executable.get_section_offsets();
+        let data = rodata.unwrap_or(text);
+        (text, data, Some(data))
+    }
+
+    fn writable_regions(&self) -> Vec<(u64, u64)> {
+        self.vm
+            .memory_mapping
+            .get_regions()
+            .iter()
+            .filter(|region| region.is_writable && region.len > 0)
+            .flat_map(|region| {
+                // gapped regions, i.e. the stack, map chunks separated by gaps of the same size
+                let chunk = if region.vm_gap_shift < 63 {
+                    1u64 << region.vm_gap_shift
+                } else {
+                    region.len
+                };
+                (0..region.len / chunk).map(move |i| (region.vm_addr + i * chunk * 2, chunk))
+            })
+            .collect()
+    }
+
+    fn backtrace(&self) -> Vec<(u64, u64)> {
+        let frames = &self.vm.frames;
+        let frame_ptrs = frames.get_frame_pointers();
+        let mut backtrace = vec![(
+            self.vm.pc_to_dbg_addr(*self.pc),
+            frame_ptrs[frames.get_frame_index()],
+        )];
+        // each caller is stopped on the call instruction just before its return pointer
+        for (i, return_ptr) in frames.get_return_ptrs().iter().enumerate().rev() {
+            backtrace.push((self.vm.pc_to_dbg_addr(return_ptr - 1), frame_ptrs[i]));
+        }
+        backtrace
+    }
+
+    fn save_frames(&self) -> Option<CallFramesState> {
+        Some(self.vm.frames.save())
+    }
+
+    fn restore_frames(&mut self, frames: &CallFramesState) {
+        self.vm.frames.restore(frames);
+    }
+
+    fn text(&self) -> (u64, &[u8]) {
+        (self.vm.program_vm_addr, self.vm.program)
+    }
+}
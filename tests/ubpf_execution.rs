@@ -3376,3 +3376,248 @@ fn test_total_chaos() {
         execute_generated_program(&program);
     }
 }
+
+#[cfg(feature = "debug")]
+#[test]
+fn test_debug_once() {
+    let program = assemble(
+        "
+        mov r0, 1
+        add r0, 2
+        mov r1, 7
+        exit",
+    )
+    .unwrap();
+    let executable = Executable::<UserError, TestInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    let report = vm
+        .debug_once(&mut TestInstructionMeter { remaining: 4 }, &[2])
+        .unwrap()
+        .unwrap();
+    assert_eq!(report.pc, 2);
+    assert_eq!(report.regs[0], 3);
+    assert_eq!(report.regs[1], 0);
+
+    // no breakpoint on the way, the program just runs to completion
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    assert_eq!(
+        vm.debug_once(&mut TestInstructionMeter { remaining: 4 }, &[])
+            .unwrap(),
+        None
+    );
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn test_set_debugger() {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport, PipeConnection};
+
+    let program = assemble(
+        "
+        mov r0, 3
+        exit",
+    )
+    .unwrap();
+    let executable = Executable::<UserError, TestInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    let (server, mut gdb) = PipeConnection::pair();
+    vm.set_debugger(DebugServerBuilder::new(DebugTransport::Connection(
+        Box::new(server),
+    )));
+    // the program waits at its entrypoint until GDB continues it
+    let gdb = std::thread::spawn(move || {
+        gdb.send_packet(b"c").unwrap();
+        gdb.recv_packet().unwrap()
+    });
+    assert_eq!(
+        vm.execute_program_interpreted(&mut TestInstructionMeter { remaining: 2 })
+            .unwrap(),
+        3
+    );
+    assert_eq!(gdb.join().unwrap(), b"W19");
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn test_debugger_watchpoint_ld_abs() {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport, PipeConnection};
+
+    let program = assemble(
+        "
+        ldabsb 0x3
+        ldabsb 0x2
+        exit",
+    )
+    .unwrap();
+    let executable = Executable::<UserError, TestInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut mem = [0x00, 0x11, 0x22, 0x33];
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut mem, &[]).unwrap();
+    let (server, mut gdb) = PipeConnection::pair();
+    vm.set_debugger(DebugServerBuilder::new(DebugTransport::Connection(
+        Box::new(server),
+    )));
+    let gdb = std::thread::spawn(move || {
+        // watch the one byte at offset 2 of the input
+        gdb.send_packet(b"Z3,400000002,1").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        // the byte at offset 3 is read first and is not watched
+        gdb.send_packet(b"c").unwrap();
+        let stop = gdb.recv_packet().unwrap();
+        gdb.send_packet(b"p0").unwrap();
+        let r0 = gdb.recv_packet().unwrap();
+        gdb.send_packet(b"c").unwrap();
+        (stop, r0, gdb.recv_packet().unwrap())
+    });
+    assert_eq!(
+        vm.execute_program_interpreted(&mut TestInstructionMeter { remaining: 3 })
+            .unwrap(),
+        0x22
+    );
+    let (stop, r0, exit) = gdb.join().unwrap();
+    assert_eq!(stop, b"T05thread:p01.01;rwatch:0400000002;");
+    // stopped right after the second load
+    assert_eq!(r0, b"2200000000000000");
+    assert_eq!(exit, b"W19");
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn test_debugger_restore_call_frames() {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport, PipeConnection};
+
+    let program = assemble(
+        "
+        mov64 r0, 0x0
+        mov64 r8, 0x1
+        lsh64 r8, 0x20
+        or64 r8, 0x38
+        callx 0x8
+        add64 r0, 0x1
+        exit
+        add64 r0, 0x10
+        exit",
+    )
+    .unwrap();
+    let executable = Executable::<UserError, TestInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    let (server, mut gdb) = PipeConnection::pair();
+    vm.set_debugger(DebugServerBuilder::new(DebugTransport::Connection(
+        Box::new(server),
+    )));
+    fn monitor(gdb: &mut PipeConnection, cmd: &str) -> Vec<u8> {
+        let hex: String = cmd.bytes().map(|b| format!("{:02x}", b)).collect();
+        gdb.send_packet(format!("qRcmd,{}", hex).as_bytes())
+            .unwrap();
+        let output = gdb.recv_packet().unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        output
+    }
+    let gdb = std::thread::spawn(move || {
+        // checkpoint on the callx, before its frame is pushed
+        gdb.send_packet(b"Z0,100000020,8").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        gdb.send_packet(b"c").unwrap();
+        gdb.recv_packet().unwrap();
+        let checkpoint = monitor(&mut gdb, "checkpoint");
+        // restore from inside the called function
+        gdb.send_packet(b"z0,100000020,8").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        gdb.send_packet(b"Z0,100000038,8").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        gdb.send_packet(b"c").unwrap();
+        gdb.recv_packet().unwrap();
+        gdb.send_packet(b"z0,100000038,8").unwrap();
+        assert_eq!(gdb.recv_packet().unwrap(), b"OK");
+        let restore = monitor(&mut gdb, "restore 0");
+        gdb.send_packet(b"c").unwrap();
+        (checkpoint, restore, gdb.recv_packet().unwrap())
+    });
+    // with the frame of the first call left behind, the return would run the
+    // add after the callx twice
+    assert_eq!(
+        vm.execute_program_interpreted(&mut TestInstructionMeter { remaining: 100 })
+            .unwrap(),
+        0x11
+    );
+    let (checkpoint, restore, exit) = gdb.join().unwrap();
+    assert_eq!(checkpoint, b"O636865636b706f696e7420300a");
+    assert!(restore.starts_with(b"O"));
+    assert_eq!(exit, b"W19");
+}
+
+#[cfg(feature = "debug")]
+#[test]
+fn test_debugger_section_offsets() {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport, PipeConnection};
+
+    let mut file = File::open("tests/elfs/noop.so").unwrap();
+    let mut elf = Vec::new();
+    file.read_to_end(&mut elf).unwrap();
+    let executable =
+        Executable::<UserError, TestInstructionMeter>::from_elf(&elf, None, Config::default())
+            .unwrap();
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    let (server, mut gdb) = PipeConnection::pair();
+    vm.set_debugger(DebugServerBuilder::new(DebugTransport::Connection(
+        Box::new(server),
+    )));
+    let gdb = std::thread::spawn(move || {
+        gdb.send_packet(b"qOffsets").unwrap();
+        let offsets = gdb.recv_packet().unwrap();
+        gdb.send_packet(b"k").unwrap();
+        // the stub gets no reply to `k`, hold the connection until it hangs up
+        assert!(gdb.recv_packet().is_err());
+        offsets
+    });
+    assert!(matches!(
+        vm.execute_program_interpreted(&mut TestInstructionMeter { remaining: 11 }),
+        Err(EbpfError::KilledByDebugger(_))
+    ));
+    // .text and .rodata are both loaded at their ELF address plus MM_PROGRAM_START
+    assert_eq!(
+        gdb.join().unwrap(),
+        b"Text=0100000000;Data=0100000000;Bss=0100000000"
+    );
+}
+
+#[cfg(all(feature = "debug", unix))]
+#[test]
+fn test_debugger_failed() {
+    use solana_rbpf::{DebugServerBuilder, DebugTransport};
+
+    let program = assemble("exit").unwrap();
+    let executable = Executable::<UserError, TestInstructionMeter>::from_text_bytes(
+        &program,
+        None,
+        Config::default(),
+    )
+    .unwrap();
+    let mut vm = EbpfVm::new(executable.as_ref(), &mut [], &[]).unwrap();
+    vm.set_debugger(DebugServerBuilder::new(DebugTransport::Unix(
+        "/nonexistent/rbpf-gdb.sock".into(),
+    )));
+    assert!(matches!(
+        vm.execute_program_interpreted(&mut TestInstructionMeter { remaining: 1 }),
+        Err(EbpfError::DebuggerFailed(_))
+    ));
+}